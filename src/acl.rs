@@ -0,0 +1,374 @@
+/// Attribute holding the ACL checked on access to an entity.
+pub const ACL_ACCESS_XATTR: &[u8] = b"system.posix_acl_access";
+/// Attribute holding the ACL new entities in a directory start with.
+pub const ACL_DEFAULT_XATTR: &[u8] = b"system.posix_acl_default";
+
+const VERSION: u32 = 2;
+const UNDEFINED_ID: u32 = u32::MAX;
+
+const TAG_USER_OBJ: u16 = 0x01;
+const TAG_USER: u16 = 0x02;
+const TAG_GROUP_OBJ: u16 = 0x04;
+const TAG_GROUP: u16 = 0x08;
+const TAG_MASK: u16 = 0x10;
+const TAG_OTHER: u16 = 0x20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: u16,
+    /// Combination of read (4), write (2) and execute (1).
+    pub perm: u16,
+    /// User or group ID of named entries, unused otherwise.
+    pub id: u32,
+}
+
+/// POSIX access control list, in the layout Linux uses for the `system.posix_acl_*`
+/// attributes: a version word followed by `(tag, perm, id)` entries sorted by tag and ID.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    /// Parses and validates an ACL attribute value.
+    ///
+    /// Returns `None` unless the list has exactly one owner, owning group and other entry,
+    /// a mask whenever named entries are present, and no duplicate named entries.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (version, data) = data.split_first_chunk::<4>()?;
+
+        if u32::from_le_bytes(*version) != VERSION || data.len() % 8 != 0 {
+            return None;
+        }
+
+        let entries: Vec<AclEntry> = data
+            .chunks_exact(8)
+            .map(|chunk| AclEntry {
+                tag: u16::from_le_bytes([chunk[0], chunk[1]]),
+                perm: u16::from_le_bytes([chunk[2], chunk[3]]),
+                id: u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]),
+            })
+            .collect();
+
+        let mut previous: Option<&AclEntry> = None;
+        let mut named = false;
+
+        for entry in &entries {
+            if entry.perm & !0o7 != 0 {
+                return None;
+            }
+
+            let unique = match entry.tag {
+                TAG_USER_OBJ | TAG_GROUP_OBJ | TAG_MASK | TAG_OTHER => true,
+                TAG_USER | TAG_GROUP => {
+                    named = true;
+                    false
+                }
+                _ => return None,
+            };
+
+            if let Some(previous) = previous {
+                let ordered = if unique {
+                    previous.tag < entry.tag
+                } else {
+                    previous.tag < entry.tag
+                        || (previous.tag == entry.tag && previous.id < entry.id)
+                };
+
+                if !ordered {
+                    return None;
+                }
+            }
+
+            previous = Some(entry);
+        }
+
+        let acl = Self { entries };
+
+        let has = |tag| acl.entry(tag).is_some();
+
+        if !has(TAG_USER_OBJ) || !has(TAG_GROUP_OBJ) || !has(TAG_OTHER) {
+            return None;
+        }
+
+        if named && !has(TAG_MASK) {
+            return None;
+        }
+
+        Some(acl)
+    }
+
+    /// The list equivalent to the `rwxrwxrwx` bits of `mode`.
+    pub fn from_mode(mode: u32) -> Self {
+        let entry = |tag, shift: u32| AclEntry {
+            tag,
+            perm: ((mode >> shift) & 0o7) as u16,
+            id: UNDEFINED_ID,
+        };
+
+        Self {
+            entries: vec![
+                entry(TAG_USER_OBJ, 6),
+                entry(TAG_GROUP_OBJ, 3),
+                entry(TAG_OTHER, 0),
+            ],
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(4 + self.entries.len() * 8);
+
+        data.extend_from_slice(&VERSION.to_le_bytes());
+
+        for entry in &self.entries {
+            let id = match entry.tag {
+                TAG_USER | TAG_GROUP => entry.id,
+                _ => UNDEFINED_ID,
+            };
+
+            data.extend_from_slice(&entry.tag.to_le_bytes());
+            data.extend_from_slice(&entry.perm.to_le_bytes());
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+
+        data
+    }
+
+    fn entry(&self, tag: u16) -> Option<&AclEntry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    fn entry_mut(&mut self, tag: u16) -> Option<&mut AclEntry> {
+        self.entries.iter_mut().find(|entry| entry.tag == tag)
+    }
+
+    /// Entry standing for the group class in the permission bits: the mask if there is one,
+    /// the owning group otherwise.
+    fn group_class_mut(&mut self) -> &mut AclEntry {
+        let tag = if self.entry(TAG_MASK).is_some() {
+            TAG_MASK
+        } else {
+            TAG_GROUP_OBJ
+        };
+
+        self.entry_mut(tag).unwrap()
+    }
+
+    /// Whether the list says nothing the permission bits can't, so it needn't be stored.
+    pub fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    /// The `rwxrwxrwx` bits equivalent to this list.
+    pub fn mode(&self) -> u32 {
+        let perm = |tag| self.entry(tag).map(|entry| entry.perm as u32).unwrap_or(0);
+        let group = self
+            .entry(TAG_MASK)
+            .map(|entry| entry.perm as u32)
+            .unwrap_or(perm(TAG_GROUP_OBJ));
+
+        (perm(TAG_USER_OBJ) << 6) | (group << 3) | perm(TAG_OTHER)
+    }
+
+    /// Rewrites the owner, group class and other entries from the `rwxrwxrwx` bits of `mode`,
+    /// as `chmod` does.
+    pub fn set_mode(&mut self, mode: u32) {
+        if let Some(entry) = self.entry_mut(TAG_USER_OBJ) {
+            entry.perm = ((mode >> 6) & 0o7) as u16;
+        }
+
+        self.group_class_mut().perm = ((mode >> 3) & 0o7) as u16;
+
+        if let Some(entry) = self.entry_mut(TAG_OTHER) {
+            entry.perm = (mode & 0o7) as u16;
+        }
+    }
+
+    /// Builds the access list of an entity created with `mode` in a directory whose default
+    /// list is `self`: the owner, group class and other entries are narrowed to the
+    /// requested bits.
+    pub fn inherit(&self, mode: u32) -> Self {
+        let mut acl = self.clone();
+
+        if let Some(entry) = acl.entry_mut(TAG_USER_OBJ) {
+            entry.perm &= ((mode >> 6) & 0o7) as u16;
+        }
+
+        acl.group_class_mut().perm &= ((mode >> 3) & 0o7) as u16;
+
+        if let Some(entry) = acl.entry_mut(TAG_OTHER) {
+            entry.perm &= (mode & 0o7) as u16;
+        }
+
+        acl
+    }
+
+    /// Checks whether a caller may access an entity with this list, following the
+    /// POSIX.1e algorithm: owner, then named users, then groups, then everyone else.
+    ///
+    /// `want` is a combination of read (4), write (2) and execute (1).
+    pub fn permits(
+        &self,
+        owner: u32,
+        owning_group: u32,
+        uid: u32,
+        groups: &[u32],
+        want: u16,
+    ) -> bool {
+        let mask = self.entry(TAG_MASK).map(|entry| entry.perm).unwrap_or(0o7);
+        let granted = |perm: u16| perm & want == want;
+
+        if uid == owner {
+            return self
+                .entry(TAG_USER_OBJ)
+                .is_some_and(|entry| granted(entry.perm));
+        }
+
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|entry| entry.tag == TAG_USER && entry.id == uid)
+        {
+            return granted(entry.perm & mask);
+        }
+
+        let mut in_group = false;
+
+        for entry in &self.entries {
+            let matches = match entry.tag {
+                TAG_GROUP_OBJ => groups.contains(&owning_group),
+                TAG_GROUP => groups.contains(&entry.id),
+                _ => false,
+            };
+
+            if matches {
+                if granted(entry.perm & mask) {
+                    return true;
+                }
+
+                in_group = true;
+            }
+        }
+
+        if in_group {
+            return false;
+        }
+
+        self.entry(TAG_OTHER)
+            .is_some_and(|entry| granted(entry.perm))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut data = VERSION.to_le_bytes().to_vec();
+
+        for &(tag, perm, id) in entries {
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&perm.to_le_bytes());
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+
+        data
+    }
+
+    /// `user::rw-`, `user:1000:rwx`, `group::r--`, `mask::r-x`, `other::---`.
+    fn extended() -> Vec<u8> {
+        encode(&[
+            (TAG_USER_OBJ, 6, UNDEFINED_ID),
+            (TAG_USER, 7, 1000),
+            (TAG_GROUP_OBJ, 4, UNDEFINED_ID),
+            (TAG_MASK, 5, UNDEFINED_ID),
+            (TAG_OTHER, 0, UNDEFINED_ID),
+        ])
+    }
+
+    #[test]
+    fn parsing_and_encoding_round_trip() {
+        let data = extended();
+        let acl = Acl::parse(&data).unwrap();
+
+        assert_eq!(acl.to_bytes(), data);
+        assert!(!acl.is_minimal());
+        assert_eq!(acl.mode(), 0o650);
+    }
+
+    #[test]
+    fn malformed_lists_are_refused() {
+        // Named entry without a mask.
+        assert!(
+            Acl::parse(&encode(&[
+                (TAG_USER_OBJ, 6, UNDEFINED_ID),
+                (TAG_USER, 7, 1000),
+                (TAG_GROUP_OBJ, 4, UNDEFINED_ID),
+                (TAG_OTHER, 0, UNDEFINED_ID),
+            ]))
+            .is_none()
+        );
+
+        // No entry for others.
+        assert!(
+            Acl::parse(&encode(&[
+                (TAG_USER_OBJ, 6, UNDEFINED_ID),
+                (TAG_GROUP_OBJ, 4, UNDEFINED_ID),
+            ]))
+            .is_none()
+        );
+
+        // Out of order.
+        assert!(
+            Acl::parse(&encode(&[
+                (TAG_GROUP_OBJ, 4, UNDEFINED_ID),
+                (TAG_USER_OBJ, 6, UNDEFINED_ID),
+                (TAG_OTHER, 0, UNDEFINED_ID),
+            ]))
+            .is_none()
+        );
+
+        assert!(Acl::parse(&[1, 0, 0, 0]).is_none());
+        assert!(Acl::parse(&extended()[..9]).is_none());
+    }
+
+    #[test]
+    fn permission_bits_make_a_minimal_list() {
+        let acl = Acl::from_mode(0o754);
+
+        assert!(acl.is_minimal());
+        assert_eq!(acl.mode(), 0o754);
+        assert_eq!(Acl::parse(&acl.to_bytes()), Some(acl));
+    }
+
+    #[test]
+    fn named_entries_are_limited_by_the_mask() {
+        let acl = Acl::parse(&extended()).unwrap();
+
+        assert!(acl.permits(0, 0, 1000, &[1000], 0o5));
+        assert!(!acl.permits(0, 0, 1000, &[1000], 0o2));
+        assert!(acl.permits(0, 0, 0, &[0], 0o6));
+        assert!(acl.permits(0, 50, 2000, &[50], 0o4));
+        assert!(!acl.permits(0, 50, 2000, &[50], 0o1));
+        assert!(!acl.permits(0, 50, 2000, &[60], 0o4));
+    }
+
+    #[test]
+    fn chmod_rewrites_the_mask_instead_of_the_group() {
+        let mut acl = Acl::parse(&extended()).unwrap();
+
+        acl.set_mode(0o700);
+
+        assert_eq!(acl.mode(), 0o700);
+        assert!(!acl.permits(0, 0, 1000, &[1000], 0o4));
+    }
+
+    #[test]
+    fn inherited_lists_are_narrowed_to_the_requested_mode() {
+        let acl = Acl::parse(&extended()).unwrap().inherit(0o640);
+
+        assert_eq!(acl.mode(), 0o640);
+        assert!(acl.permits(0, 0, 1000, &[1000], 0o4));
+        assert!(!acl.permits(0, 0, 1000, &[1000], 0o1));
+    }
+}
@@ -46,3 +46,27 @@ impl io::Seek for FileDevice {
 }
 
 impl Device for FileDevice {}
+
+/// Devices that can push what was written to them down to stable storage.
+pub trait StableStorage {
+    /// Waits until written data, and unless `data_only` the metadata of the backing file too,
+    /// survives a crash.
+    fn sync(&self, data_only: bool) -> std::io::Result<()>;
+}
+
+impl FileDevice {
+    /// Opens another handle on the same image, for syncing it while NoctFS holds this one.
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Self(self.0.try_clone()?))
+    }
+}
+
+impl StableStorage for FileDevice {
+    fn sync(&self, data_only: bool) -> std::io::Result<()> {
+        if data_only {
+            self.0.sync_data()
+        } else {
+            self.0.sync_all()
+        }
+    }
+}
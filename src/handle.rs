@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use fuser::FileType;
+use noctfs::{BlockAddress, entity::Entity};
+
+/// One entry of a directory listing taken for `readdir`.
+#[derive(Clone, Debug)]
+pub struct DirectoryEntry {
+    pub ino: u64,
+    pub kind: FileType,
+    pub name: String,
+}
+
+/// State of an open file or directory.
+pub struct OpenHandle {
+    pub ino: u64,
+    /// Inode of the directory the entity was in when it was opened.
+    pub parent_ino: u64,
+    /// The entity and the block of its directory, as of the last time the handle used them.
+    /// Dropped whenever the entity's header may have changed.
+    pub entity: Option<(BlockAddress, Entity)>,
+    /// Flags the handle was opened with.
+    pub flags: i32,
+    /// Opened with `O_APPEND`: every write goes to the end of the file.
+    pub append: bool,
+    /// Written data not passed on to NoctFS yet, which goes at `write_offset`.
+    pub write_buffer: Vec<u8>,
+    pub write_offset: u64,
+    /// Error passing the buffer on failed with, not reported to the writer yet.
+    pub write_error: Option<i32>,
+    /// The file was closed, but the buffer couldn't be passed on. The handle stays until
+    /// it can, since nothing else has the data.
+    pub released: bool,
+    /// Listing handed out by `readdir`.
+    pub directory: Option<Vec<DirectoryEntry>>,
+    /// Whether `readdir` handed out entries of `directory` yet.
+    pub directory_read: bool,
+}
+
+impl OpenHandle {
+    /// End of the data waiting in the write buffer, if there is any.
+    pub fn pending_end(&self) -> Option<u64> {
+        (!self.write_buffer.is_empty()).then(|| self.write_offset + self.write_buffer.len() as u64)
+    }
+}
+
+/// Open handles by `fh`.
+#[derive(Default)]
+pub struct HandleTable {
+    handles: HashMap<u64, OpenHandle>,
+    next_fh: u64,
+}
+
+impl HandleTable {
+    /// Opens a new handle on `ino` and returns its `fh`.
+    pub fn open(&mut self, ino: u64, parent_ino: u64, flags: i32, append: bool) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+
+        println!("Allocated fh: {fh} with ino: {ino}");
+
+        self.handles.insert(
+            fh,
+            OpenHandle {
+                ino,
+                parent_ino,
+                entity: None,
+                flags,
+                append,
+                write_buffer: vec![],
+                write_offset: 0,
+                write_error: None,
+                released: false,
+                directory: None,
+                directory_read: false,
+            },
+        );
+
+        fh
+    }
+
+    pub fn get(&self, fh: u64) -> Option<&OpenHandle> {
+        self.handles.get(&fh)
+    }
+
+    pub fn get_mut(&mut self, fh: u64) -> Option<&mut OpenHandle> {
+        self.handles.get_mut(&fh)
+    }
+
+    pub fn close(&mut self, fh: u64) -> Option<OpenHandle> {
+        println!("Freeing fh: {fh}");
+
+        self.handles.remove(&fh)
+    }
+
+    /// Takes the error a write through `fh` failed with, so it's reported only once.
+    pub fn take_write_error(&mut self, fh: u64) -> Option<i32> {
+        self.handles.get_mut(&fh)?.write_error.take()
+    }
+
+    /// Released handles whose buffer has been passed on by now, and which can go.
+    pub fn released_and_flushed(&self) -> Vec<u64> {
+        self.handles
+            .iter()
+            .filter(|(_, handle)| handle.released && handle.write_buffer.is_empty())
+            .map(|(&fh, _)| fh)
+            .collect()
+    }
+
+    /// Whether any handle still refers to `ino`.
+    pub fn is_open(&self, ino: u64) -> bool {
+        self.handles.values().any(|handle| handle.ino == ino)
+    }
+
+    /// Handles on `ino` with writes waiting in their buffer.
+    pub fn with_pending_writes(&self, ino: u64) -> Vec<u64> {
+        self.handles
+            .iter()
+            .filter(|(_, handle)| handle.ino == ino && !handle.write_buffer.is_empty())
+            .map(|(&fh, _)| fh)
+            .collect()
+    }
+
+    /// Every handle with writes waiting in its buffer.
+    pub fn all_with_pending_writes(&self) -> Vec<u64> {
+        self.handles
+            .iter()
+            .filter(|(_, handle)| !handle.write_buffer.is_empty())
+            .map(|(&fh, _)| fh)
+            .collect()
+    }
+
+    /// Drops the cached copies of the entity starting at `block`, after its header changed.
+    pub fn forget_entity(&mut self, block: BlockAddress) {
+        for handle in self.handles.values_mut() {
+            if handle
+                .entity
+                .as_ref()
+                .is_some_and(|(_, entity)| entity.start_block == block)
+            {
+                handle.entity = None;
+            }
+        }
+    }
+}
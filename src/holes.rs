@@ -0,0 +1,250 @@
+/// Ranges of a file that were never written to, as whole blocks.
+///
+/// This is bookkeeping only, so that `SEEK_DATA`/`SEEK_HOLE` can tell tools like
+/// `cp --sparse` where to skip. NoctFS stores every file as a single block chain, so a hole
+/// still takes up blocks on the image (and in `st_blocks`) and reads back as the zeros it
+/// was filled with; growing a file writes all of those zeros out before the call returns.
+///
+/// Ranges are half-open, sorted and never touch each other.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HoleMap {
+    ranges: Vec<(u64, u64)>,
+}
+
+impl HoleMap {
+    /// Builds a map out of stored ranges, which must be sorted and apart from each other.
+    pub fn from_ranges(ranges: Vec<(u64, u64)>) -> Option<Self> {
+        let valid = ranges.iter().all(|&(start, end)| start < end)
+            && ranges.windows(2).all(|pair| pair[0].1 < pair[1].0);
+
+        valid.then_some(Self { ranges })
+    }
+
+    pub fn ranges(&self) -> &[(u64, u64)] {
+        &self.ranges
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Marks `start..end` of a file `size` bytes long as a hole.
+    ///
+    /// Only the blocks lying wholly inside the range become one, except for the last block of
+    /// the file, which counts as whole.
+    pub fn punch(&mut self, start: u64, end: u64, block_size: u64, size: u64) {
+        let requested_start = start;
+        let start = start.next_multiple_of(block_size);
+        let end = if end >= size {
+            size
+        } else {
+            end - end % block_size
+        };
+
+        if start >= end {
+            return;
+        }
+
+        let (mut start, mut end) = (start, end);
+
+        // Swallow every range that overlaps or touches the new one, including one ending in
+        // the partial block the new one starts after.
+        self.ranges.retain(|&(hole_start, hole_end)| {
+            if hole_end < requested_start || hole_start > end {
+                return true;
+            }
+
+            start = start.min(hole_start);
+            end = end.max(hole_end);
+
+            false
+        });
+
+        let position = self
+            .ranges
+            .partition_point(|&(hole_start, _)| hole_start < start);
+        self.ranges.insert(position, (start, end));
+    }
+
+    /// Marks `start..end` as written, along with the rest of the blocks it touches.
+    ///
+    /// Returns `false` if nothing changed.
+    pub fn fill(&mut self, start: u64, end: u64, block_size: u64) -> bool {
+        if start >= end || self.ranges.is_empty() {
+            return false;
+        }
+
+        let start = start - start % block_size;
+        let end = end.next_multiple_of(block_size);
+
+        let mut changed = false;
+        let mut ranges = Vec::with_capacity(self.ranges.len() + 1);
+
+        for &(hole_start, hole_end) in &self.ranges {
+            if hole_end <= start || hole_start >= end {
+                ranges.push((hole_start, hole_end));
+                continue;
+            }
+
+            changed = true;
+
+            if hole_start < start {
+                ranges.push((hole_start, start));
+            }
+
+            if hole_end > end {
+                ranges.push((end, hole_end));
+            }
+        }
+
+        self.ranges = ranges;
+
+        changed
+    }
+
+    /// Drops everything past `size` after the file was cut short.
+    pub fn truncate(&mut self, size: u64) {
+        self.ranges.retain(|&(start, _)| start < size);
+
+        if let Some(last) = self.ranges.last_mut() {
+            last.1 = last.1.min(size);
+        }
+    }
+
+    /// Where the data at or after `offset` starts in a file `size` bytes long.
+    /// `None` means there is no more data.
+    pub fn next_data(&self, offset: u64, size: u64) -> Option<u64> {
+        if offset >= size {
+            return None;
+        }
+
+        match self
+            .ranges
+            .iter()
+            .find(|&&(start, end)| start <= offset && offset < end)
+        {
+            Some(&(_, end)) => (end < size).then_some(end),
+            None => Some(offset),
+        }
+    }
+
+    /// Where the hole at or after `offset` starts in a file `size` bytes long.
+    /// The end of the file counts as one; `None` means `offset` lies past it.
+    pub fn next_hole(&self, offset: u64, size: u64) -> Option<u64> {
+        if offset >= size {
+            return None;
+        }
+
+        let hole = self
+            .ranges
+            .iter()
+            .find(|&&(_, end)| offset < end)
+            .map(|&(start, _)| start.max(offset))
+            .unwrap_or(size);
+
+        Some(hole.min(size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: u64 = 4096;
+
+    fn holes(ranges: &[(u64, u64)]) -> HoleMap {
+        HoleMap::from_ranges(ranges.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn only_whole_blocks_become_holes() {
+        let mut map = HoleMap::default();
+
+        map.punch(100, 3 * BLOCK + 10, BLOCK, 10 * BLOCK);
+        assert_eq!(map.ranges(), &[(BLOCK, 3 * BLOCK)]);
+
+        map.punch(5 * BLOCK + 10, 5 * BLOCK + 100, BLOCK, 10 * BLOCK);
+        assert_eq!(map.ranges(), &[(BLOCK, 3 * BLOCK)]);
+    }
+
+    #[test]
+    fn the_last_block_counts_as_whole() {
+        let mut map = HoleMap::default();
+
+        map.punch(BLOCK, 20_000, BLOCK, 10_000);
+
+        assert_eq!(map.ranges(), &[(BLOCK, 10_000)]);
+    }
+
+    #[test]
+    fn punching_merges_with_neighbouring_holes() {
+        let mut map = holes(&[(BLOCK, 2 * BLOCK), (4 * BLOCK, 5 * BLOCK)]);
+
+        map.punch(2 * BLOCK, 4 * BLOCK, BLOCK, 10 * BLOCK);
+
+        assert_eq!(map.ranges(), &[(BLOCK, 5 * BLOCK)]);
+    }
+
+    #[test]
+    fn growing_extends_a_hole_ending_in_the_old_last_block() {
+        let mut map = holes(&[(BLOCK, 5_000)]);
+
+        // The gap starts inside the block the old hole ended in.
+        map.punch(5_000, 20_000, BLOCK, 20_000);
+
+        assert_eq!(map.ranges(), &[(BLOCK, 20_000)]);
+    }
+
+    #[test]
+    fn filling_splits_a_hole_at_block_boundaries() {
+        let mut map = holes(&[(0, 5 * BLOCK)]);
+
+        assert!(map.fill(BLOCK + 10, 2 * BLOCK + 1, BLOCK));
+        assert_eq!(map.ranges(), &[(0, BLOCK), (3 * BLOCK, 5 * BLOCK)]);
+
+        assert!(!map.fill(BLOCK, 3 * BLOCK, BLOCK));
+        assert!(map.fill(0, 10 * BLOCK, BLOCK));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn truncating_drops_and_cuts_holes_past_the_end() {
+        let mut map = holes(&[(0, BLOCK), (2 * BLOCK, 4 * BLOCK), (5 * BLOCK, 6 * BLOCK)]);
+
+        map.truncate(10_000);
+        assert_eq!(map.ranges(), &[(0, BLOCK), (2 * BLOCK, 10_000)]);
+
+        map.truncate(BLOCK);
+        assert_eq!(map.ranges(), &[(0, BLOCK)]);
+    }
+
+    #[test]
+    fn data_starts_after_holes() {
+        let map = holes(&[(0, BLOCK), (2 * BLOCK, 3 * BLOCK)]);
+        let size = 3 * BLOCK;
+
+        assert_eq!(map.next_data(0, size), Some(BLOCK));
+        assert_eq!(map.next_data(5_000, size), Some(5_000));
+        assert_eq!(map.next_data(2 * BLOCK + 1, size), None);
+        assert_eq!(map.next_data(size, size), None);
+    }
+
+    #[test]
+    fn holes_start_at_the_next_range_or_the_end() {
+        let map = holes(&[(0, BLOCK), (2 * BLOCK, 3 * BLOCK)]);
+        let size = 4 * BLOCK;
+
+        assert_eq!(map.next_hole(10, size), Some(10));
+        assert_eq!(map.next_hole(5_000, size), Some(2 * BLOCK));
+        assert_eq!(map.next_hole(3 * BLOCK, size), Some(size));
+        assert_eq!(map.next_hole(size, size), None);
+    }
+
+    #[test]
+    fn stored_ranges_must_be_sorted_and_apart() {
+        assert!(HoleMap::from_ranges(vec![(0, BLOCK), (BLOCK, 2 * BLOCK)]).is_none());
+        assert!(HoleMap::from_ranges(vec![(2 * BLOCK, 3 * BLOCK), (0, BLOCK)]).is_none());
+        assert!(HoleMap::from_ranges(vec![(BLOCK, BLOCK)]).is_none());
+        assert!(HoleMap::from_ranges(vec![(0, BLOCK), (2 * BLOCK, 3 * BLOCK)]).is_some());
+    }
+}
@@ -1,36 +1,339 @@
+use std::collections::HashMap;
+
+use noctfs::BlockAddress;
+
+pub const ROOT_INO: u64 = 1;
+
 pub struct CacheEntry {
     pub ino: u64,
     pub parent_ino: u64,
+    pub block: BlockAddress,
+    pub generation: u64,
 }
 
+/// Index of every known inode to its parent directory and current location.
+///
+/// Inode numbers are handed out by this table rather than taken from the entity's
+/// `start_block`, so they stay the same while a file's blocks move around. A freed number
+/// is only reused with a bumped generation, so clients can tell the new file from the old one,
+/// and not before the kernel forgot every lookup of it.
+/// The numbering is saved along with the metadata and brought back with [`INOCache::restore`]
+/// and [`INOCache::insert_known`], so it also survives remounts.
+///
+/// Filled lazily from directory listings and kept in sync by mutating operations,
+/// so an entity can be resolved at any depth without walking the whole tree.
 pub struct INOCache {
-    container: Vec<CacheEntry>,
+    container: HashMap<u64, CacheEntry>,
+    by_block: HashMap<BlockAddress, u64>,
+    free: Vec<(u64, u64)>, // (ino, next generation)
+    next_ino: u64,
+    /// Lookups of each inode the kernel hasn't forgotten yet.
+    lookups: HashMap<u64, u64>,
+    /// Removed inodes the kernel still knows, with the generation they come back with.
+    retired: HashMap<u64, u64>,
 }
 
-impl Default for INOCache {
-    fn default() -> Self {
-        Self::new()
+impl INOCache {
+    pub fn new(root_block: BlockAddress) -> Self {
+        let mut cache = Self {
+            container: HashMap::with_capacity(256),
+            by_block: HashMap::with_capacity(256),
+            free: vec![],
+            next_ino: ROOT_INO + 1,
+            lookups: HashMap::new(),
+            retired: HashMap::new(),
+        };
+
+        cache.insert(ROOT_INO, ROOT_INO, root_block, 0);
+
+        cache
     }
-}
 
-impl INOCache {
-    pub fn new() -> Self {
-        Self {
-            container: Vec::with_capacity(256),
+    fn insert(&mut self, ino: u64, parent_ino: u64, block: BlockAddress, generation: u64) {
+        self.by_block.insert(block, ino);
+        self.container.insert(
+            ino,
+            CacheEntry {
+                ino,
+                parent_ino,
+                block,
+                generation,
+            },
+        );
+    }
+
+    /// Returns the inode of the entity starting at `block`, allocating one if it has none yet.
+    pub fn add(&mut self, parent_ino: u64, block: BlockAddress) -> u64 {
+        if let Some(&ino) = self.by_block.get(&block) {
+            if let Some(entry) = self.container.get_mut(&ino) {
+                entry.parent_ino = parent_ino;
+            }
+
+            return ino;
+        }
+
+        let (ino, generation) = self.free.pop().unwrap_or_else(|| {
+            let ino = self.next_ino;
+            self.next_ino += 1;
+
+            (ino, 0)
+        });
+
+        self.insert(ino, parent_ino, block, generation);
+
+        ino
+    }
+
+    /// Indexes the entity at `block` under the inode it was given on an earlier mount.
+    ///
+    /// Returns `false` if that number is already taken, in which case nothing changes.
+    pub fn insert_known(
+        &mut self,
+        ino: u64,
+        parent_ino: u64,
+        block: BlockAddress,
+        generation: u64,
+    ) -> bool {
+        if ino <= ROOT_INO || self.container.contains_key(&ino) || self.retired.contains_key(&ino) {
+            return false;
+        }
+
+        self.free.retain(|&(free, _)| free != ino);
+        self.next_ino = self.next_ino.max(ino + 1);
+        self.insert(ino, parent_ino, block, generation);
+
+        true
+    }
+
+    /// Picks up the numbering saved on an earlier mount: the next unused inode and the
+    /// freed ones with the generation they come back with.
+    pub fn restore(&mut self, next_ino: u64, free: Vec<(u64, u64)>) {
+        self.next_ino = self.next_ino.max(next_ino);
+        self.free = free
+            .into_iter()
+            .filter(|(ino, _)| *ino > ROOT_INO && !self.container.contains_key(ino))
+            .collect();
+    }
+
+    /// The numbering to save, as [`INOCache::restore`] takes it. The kernel forgets every
+    /// inode on unmount, so the ones it still knows are free by the time this is loaded.
+    pub fn numbering(&self) -> (u64, Vec<(u64, u64)>) {
+        let mut free = self.free.clone();
+        free.extend(
+            self.retired
+                .iter()
+                .map(|(&ino, &generation)| (ino, generation)),
+        );
+
+        (self.next_ino, free)
+    }
+
+    /// Notes that the kernel was handed `ino` in reply to a lookup, or to anything else
+    /// creating an entry.
+    pub fn count_lookup(&mut self, ino: u64) {
+        if ino != ROOT_INO {
+            *self.lookups.entry(ino).or_default() += 1;
+        }
+    }
+
+    /// Takes back `nlookup` lookups of `ino`. Once the kernel forgot them all, a removed
+    /// inode's number can be handed out again.
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        let Some(lookups) = self.lookups.get_mut(&ino) else {
+            return;
+        };
+
+        *lookups = lookups.saturating_sub(nlookup);
+
+        if *lookups != 0 {
+            return;
+        }
+
+        self.lookups.remove(&ino);
+
+        if let Some(generation) = self.retired.remove(&ino) {
+            self.free.push((ino, generation));
         }
     }
 
-    pub fn add(&mut self, parent_ino: u64, ino: u64) {
-        if self.container.len() > 256 {
-            self.container.remove(0);
+    /// Points an existing inode at a new parent and/or starting block.
+    pub fn relocate(&mut self, ino: u64, parent_ino: u64, block: BlockAddress) {
+        let Some(entry) = self.container.get_mut(&ino) else {
+            return;
+        };
+
+        if self.by_block.get(&entry.block) == Some(&ino) {
+            self.by_block.remove(&entry.block);
+        }
+
+        entry.parent_ino = parent_ino;
+        entry.block = block;
+
+        self.by_block.insert(block, ino);
+    }
+
+    pub fn remove(&mut self, ino: u64) {
+        if ino == ROOT_INO {
+            return;
+        }
+
+        if let Some(entry) = self.container.remove(&ino) {
+            if self.by_block.get(&entry.block) == Some(&ino) {
+                self.by_block.remove(&entry.block);
+            }
+
+            if self.lookups.contains_key(&ino) {
+                self.retired.insert(ino, entry.generation + 1);
+            } else {
+                self.free.push((ino, entry.generation + 1));
+            }
         }
-        
-        self.container.push(CacheEntry { ino, parent_ino });
     }
 
-    pub fn find_parent(&mut self, ino: u64) -> Option<u64> {
-        self.container
-            .iter().find(|a| a.ino == ino)
-            .map(|a| a.parent_ino)
+    pub fn find_parent(&self, ino: u64) -> Option<u64> {
+        self.container.get(&ino).map(|a| a.parent_ino)
+    }
+
+    pub fn find_block(&self, ino: u64) -> Option<BlockAddress> {
+        self.container.get(&ino).map(|a| a.block)
+    }
+
+    pub fn find_ino(&self, block: BlockAddress) -> Option<u64> {
+        self.by_block.get(&block).copied()
+    }
+
+    pub fn generation(&self, ino: u64) -> u64 {
+        self.container.get(&ino).map(|a| a.generation).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_are_handed_out_once_per_block() {
+        let mut cache = INOCache::new(100);
+
+        let first = cache.add(ROOT_INO, 200);
+        let second = cache.add(ROOT_INO, 300);
+
+        assert_ne!(first, ROOT_INO);
+        assert_ne!(first, second);
+        assert_eq!(cache.add(ROOT_INO, 200), first);
+        assert_eq!(cache.find_ino(100), Some(ROOT_INO));
+        assert_eq!(cache.find_block(second), Some(300));
+    }
+
+    #[test]
+    fn a_reused_number_comes_back_with_a_new_generation() {
+        let mut cache = INOCache::new(100);
+
+        let ino = cache.add(ROOT_INO, 200);
+        let generation = cache.generation(ino);
+
+        cache.remove(ino);
+        assert_eq!(cache.find_block(ino), None);
+        assert_eq!(cache.find_ino(200), None);
+
+        assert_eq!(cache.add(ROOT_INO, 300), ino);
+        assert_eq!(cache.generation(ino), generation + 1);
+    }
+
+    #[test]
+    fn a_number_is_only_reused_once_the_kernel_forgot_it() {
+        let mut cache = INOCache::new(100);
+
+        let ino = cache.add(ROOT_INO, 200);
+        cache.count_lookup(ino);
+        cache.count_lookup(ino);
+        cache.remove(ino);
+
+        assert!(!cache.insert_known(ino, ROOT_INO, 300, 0));
+        assert_ne!(cache.add(ROOT_INO, 300), ino);
+
+        cache.forget(ino, 1);
+        assert_ne!(cache.add(ROOT_INO, 400), ino);
+
+        cache.forget(ino, 1);
+        assert_eq!(cache.add(ROOT_INO, 500), ino);
+        assert_eq!(cache.generation(ino), 1);
+    }
+
+    #[test]
+    fn forgetting_a_live_inode_keeps_it() {
+        let mut cache = INOCache::new(100);
+
+        let ino = cache.add(ROOT_INO, 200);
+        cache.count_lookup(ino);
+        cache.forget(ino, 1);
+
+        assert_eq!(cache.find_block(ino), Some(200));
+        assert_ne!(cache.add(ROOT_INO, 300), ino);
+    }
+
+    #[test]
+    fn numbers_the_kernel_still_knows_are_saved_as_free() {
+        let mut cache = INOCache::new(100);
+
+        let ino = cache.add(ROOT_INO, 200);
+        cache.count_lookup(ino);
+        cache.remove(ino);
+
+        assert_eq!(cache.numbering(), (ino + 1, vec![(ino, 1)]));
+    }
+
+    #[test]
+    fn the_root_is_never_removed() {
+        let mut cache = INOCache::new(100);
+
+        cache.remove(ROOT_INO);
+
+        assert_eq!(cache.find_block(ROOT_INO), Some(100));
+    }
+
+    #[test]
+    fn relocating_follows_the_entity() {
+        let mut cache = INOCache::new(100);
+
+        let directory = cache.add(ROOT_INO, 200);
+        let ino = cache.add(ROOT_INO, 300);
+
+        cache.relocate(ino, directory, 400);
+
+        assert_eq!(cache.find_parent(ino), Some(directory));
+        assert_eq!(cache.find_block(ino), Some(400));
+        assert_eq!(cache.find_ino(400), Some(ino));
+        assert_eq!(cache.find_ino(300), None);
+    }
+
+    #[test]
+    fn known_numbers_are_kept_unless_taken() {
+        let mut cache = INOCache::new(100);
+
+        assert!(cache.insert_known(10, ROOT_INO, 200, 3));
+        assert_eq!(cache.find_ino(200), Some(10));
+        assert_eq!(cache.generation(10), 3);
+
+        assert!(!cache.insert_known(10, ROOT_INO, 300, 0));
+        assert!(!cache.insert_known(ROOT_INO, ROOT_INO, 300, 0));
+        assert_eq!(cache.find_ino(300), None);
+
+        // New numbers start past the highest known one.
+        assert_eq!(cache.add(ROOT_INO, 300), 11);
+    }
+
+    #[test]
+    fn restored_numbering_skips_numbers_in_use() {
+        let mut cache = INOCache::new(100);
+
+        assert!(cache.insert_known(5, ROOT_INO, 200, 0));
+        cache.restore(8, vec![(ROOT_INO, 1), (5, 1), (6, 2)]);
+
+        assert_eq!(cache.numbering(), (8, vec![(6, 2)]));
+
+        let ino = cache.add(ROOT_INO, 300);
+        assert_eq!((ino, cache.generation(ino)), (6, 2));
+        assert_eq!(cache.add(ROOT_INO, 400), 8);
     }
 }
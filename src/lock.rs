@@ -0,0 +1,291 @@
+use std::collections::{HashMap, HashSet};
+
+use fuser::ReplyEmpty;
+use libc::{EINTR, F_UNLCK, F_WRLCK};
+
+/// A byte-range lock held by one lock owner. `end` is inclusive.
+#[derive(Clone, Copy, Debug)]
+pub struct Lock {
+    pub owner: u64,
+    pub pid: u32,
+    pub start: u64,
+    pub end: u64,
+    /// `F_RDLCK` or `F_WRLCK`.
+    pub typ: i32,
+}
+
+impl Lock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts_with(&self, other: &Lock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.typ == F_WRLCK || other.typ == F_WRLCK)
+    }
+}
+
+/// An `F_SETLKW` request waiting for conflicting locks to go away.
+struct Waiter {
+    lock: Lock,
+    reply: ReplyEmpty,
+}
+
+/// POSIX record locks of every inode.
+///
+/// Locks belong to a lock owner, so one owner never conflicts with itself and setting
+/// a lock over a range it already holds replaces that part, as `fcntl` does.
+#[derive(Default)]
+pub struct LockManager {
+    locks: HashMap<u64, Vec<Lock>>,
+    waiters: HashMap<u64, Vec<Waiter>>,
+}
+
+impl LockManager {
+    /// Returns a lock on `ino` that keeps `lock` from being taken.
+    pub fn conflict(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        self.locks
+            .get(&ino)?
+            .iter()
+            .find(|held| held.conflicts_with(lock))
+            .copied()
+    }
+
+    /// Takes `lock` on `ino`, or drops the owner's locks in its range for `F_UNLCK`,
+    /// then hands out whatever waiting locks became free.
+    ///
+    /// Conflicts have to be checked beforehand.
+    pub fn set(&mut self, ino: u64, lock: Lock) {
+        self.apply(ino, lock);
+        self.wake(ino);
+    }
+
+    /// Queues `lock` to be taken once nothing conflicts with it, replying only then.
+    pub fn wait(&mut self, ino: u64, lock: Lock, reply: ReplyEmpty) {
+        self.waiters
+            .entry(ino)
+            .or_default()
+            .push(Waiter { lock, reply });
+    }
+
+    /// Whether waiting for `lock` on `ino` would close a cycle of owners each waiting for
+    /// a lock the next one holds, none of which could ever be granted.
+    pub fn would_deadlock(&self, ino: u64, lock: &Lock) -> bool {
+        let mut pending = self.blockers(ino, lock);
+        let mut seen = HashSet::new();
+
+        while let Some(owner) = pending.pop() {
+            if owner == lock.owner {
+                return true;
+            }
+
+            if !seen.insert(owner) {
+                continue;
+            }
+
+            for (&waiting_ino, waiters) in &self.waiters {
+                for waiter in waiters.iter().filter(|waiter| waiter.lock.owner == owner) {
+                    pending.extend(self.blockers(waiting_ino, &waiter.lock));
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Drops every lock `owner` holds on `ino`, as closing a file does. Waits the owner
+    /// still has queued on it are given up with `EINTR`.
+    pub fn release_owner(&mut self, ino: u64, owner: u64) {
+        if let Some(waiters) = self.waiters.remove(&ino) {
+            let (given_up, still_waiting): (Vec<_>, Vec<_>) = waiters
+                .into_iter()
+                .partition(|waiter| waiter.lock.owner == owner);
+
+            for waiter in given_up {
+                waiter.reply.error(EINTR);
+            }
+
+            if !still_waiting.is_empty() {
+                self.waiters.insert(ino, still_waiting);
+            }
+        }
+
+        let Some(locks) = self.locks.get_mut(&ino) else {
+            return;
+        };
+
+        let count = locks.len();
+        locks.retain(|lock| lock.owner != owner);
+
+        let released = locks.len() != count;
+
+        if locks.is_empty() {
+            self.locks.remove(&ino);
+        }
+
+        if released {
+            self.wake(ino);
+        }
+    }
+
+    /// Owners of the locks on `ino` that keep `lock` from being taken.
+    fn blockers(&self, ino: u64, lock: &Lock) -> Vec<u64> {
+        self.locks
+            .get(&ino)
+            .into_iter()
+            .flatten()
+            .filter(|held| held.conflicts_with(lock))
+            .map(|held| held.owner)
+            .collect()
+    }
+
+    fn apply(&mut self, ino: u64, lock: Lock) {
+        let locks = self.locks.entry(ino).or_default();
+        let mut kept = Vec::with_capacity(locks.len() + 2);
+
+        for held in locks.drain(..) {
+            if held.owner != lock.owner || !held.overlaps(lock.start, lock.end) {
+                kept.push(held);
+                continue;
+            }
+
+            // Keep whatever sticks out on either side of the new range.
+            if held.start < lock.start {
+                kept.push(Lock {
+                    end: lock.start - 1,
+                    ..held
+                });
+            }
+
+            if held.end > lock.end {
+                kept.push(Lock {
+                    start: lock.end + 1,
+                    ..held
+                });
+            }
+        }
+
+        if lock.typ != F_UNLCK {
+            kept.push(lock);
+        }
+
+        if kept.is_empty() {
+            self.locks.remove(&ino);
+        } else {
+            *locks = kept;
+        }
+    }
+
+    /// Grants waiting locks on `ino` that nothing conflicts with anymore, oldest first.
+    fn wake(&mut self, ino: u64) {
+        let Some(waiters) = self.waiters.remove(&ino) else {
+            return;
+        };
+
+        let mut still_waiting = vec![];
+
+        for waiter in waiters {
+            if self.conflict(ino, &waiter.lock).is_some() {
+                still_waiting.push(waiter);
+                continue;
+            }
+
+            self.apply(ino, waiter.lock);
+            waiter.reply.ok();
+        }
+
+        if !still_waiting.is_empty() {
+            self.waiters.insert(ino, still_waiting);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::F_RDLCK;
+
+    use super::*;
+
+    fn lock(owner: u64, start: u64, end: u64, typ: i32) -> Lock {
+        Lock {
+            owner,
+            pid: owner as u32,
+            start,
+            end,
+            typ,
+        }
+    }
+
+    #[test]
+    fn readers_share_and_writers_exclude() {
+        let mut locks = LockManager::default();
+
+        locks.set(1, lock(1, 0, 99, F_RDLCK));
+
+        assert!(locks.conflict(1, &lock(2, 50, 60, F_RDLCK)).is_none());
+        assert!(locks.conflict(1, &lock(2, 50, 60, F_WRLCK)).is_some());
+        assert!(locks.conflict(1, &lock(2, 100, 200, F_WRLCK)).is_none());
+        assert!(locks.conflict(2, &lock(2, 50, 60, F_WRLCK)).is_none());
+    }
+
+    #[test]
+    fn an_owner_never_conflicts_with_itself() {
+        let mut locks = LockManager::default();
+
+        locks.set(1, lock(1, 0, 99, F_WRLCK));
+
+        assert!(locks.conflict(1, &lock(1, 0, 99, F_WRLCK)).is_none());
+    }
+
+    #[test]
+    fn unlocking_the_middle_keeps_both_ends() {
+        let mut locks = LockManager::default();
+
+        locks.set(1, lock(1, 0, 99, F_WRLCK));
+        locks.set(1, lock(1, 40, 59, F_UNLCK));
+
+        assert!(locks.conflict(1, &lock(2, 40, 59, F_WRLCK)).is_none());
+
+        let head = locks.conflict(1, &lock(2, 0, 39, F_WRLCK)).unwrap();
+        assert_eq!((head.start, head.end), (0, 39));
+
+        let tail = locks.conflict(1, &lock(2, 60, 99, F_WRLCK)).unwrap();
+        assert_eq!((tail.start, tail.end), (60, 99));
+    }
+
+    #[test]
+    fn a_new_type_replaces_the_old_one_in_its_range() {
+        let mut locks = LockManager::default();
+
+        locks.set(1, lock(1, 0, 99, F_WRLCK));
+        locks.set(1, lock(1, 0, 49, F_RDLCK));
+
+        assert!(locks.conflict(1, &lock(2, 0, 49, F_RDLCK)).is_none());
+        assert!(locks.conflict(1, &lock(2, 50, 99, F_RDLCK)).is_some());
+    }
+
+    #[test]
+    fn releasing_an_owner_only_drops_its_own_locks() {
+        let mut locks = LockManager::default();
+
+        locks.set(1, lock(1, 0, 9, F_WRLCK));
+        locks.set(1, lock(2, 10, 19, F_WRLCK));
+        locks.set(2, lock(1, 0, 9, F_WRLCK));
+
+        locks.release_owner(1, 1);
+
+        assert!(locks.conflict(1, &lock(3, 0, 9, F_WRLCK)).is_none());
+        assert!(locks.conflict(1, &lock(3, 10, 19, F_WRLCK)).is_some());
+        assert!(locks.conflict(2, &lock(3, 0, 9, F_WRLCK)).is_some());
+    }
+
+    #[test]
+    fn nothing_deadlocks_without_waiters() {
+        let mut locks = LockManager::default();
+
+        locks.set(1, lock(1, 0, 9, F_WRLCK));
+
+        assert!(!locks.would_deadlock(1, &lock(2, 0, 9, F_WRLCK)));
+    }
+}
@@ -1,408 +1,2381 @@
+pub mod acl;
+pub mod handle;
+pub mod holes;
 pub mod ino_cache;
-
-use ino_cache::INOCache;
+pub mod lock;
+pub mod meta;
+
+use acl::{ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR, Acl};
+use device::{FileDevice, StableStorage};
+use handle::{DirectoryEntry, HandleTable};
+use ino_cache::{INOCache, ROOT_INO};
+use lock::{Lock, LockManager};
+use meta::{
+    AtimePolicy, EntityMeta, FS_APPEND_FL, FS_IMMUTABLE_FL, FS_NODUMP_FL, META_FILE_NAME,
+    META_NEW_FILE_NAME, MetaStore,
+};
 use noctfs::{self, BlockAddress, NoctFS, entity::Entity};
 
 use std::{
+    collections::HashMap,
     ffi::OsStr,
     io,
-    time::{Duration, SystemTime},
+    os::unix::ffi::OsStrExt,
+    time::{Duration, Instant, SystemTime},
 };
 
 use fuser::{FileAttr, FileType, Filesystem, MountOption, Request};
-use libc::{EIO, ENOENT, ENOSYS, O_ACCMODE, O_RDONLY, O_RDWR, O_WRONLY};
+use libc::{
+    E2BIG, EACCES, EAGAIN, EBADF, EDEADLK, EEXIST, EINVAL, EIO, EISDIR, EMLINK, ENAMETOOLONG,
+    ENODATA, ENOENT, ENOSPC, ENOSYS, ENOTDIR, ENOTEMPTY, ENOTTY, ENXIO, EOPNOTSUPP, EPERM, ERANGE,
+    F_OK, F_RDLCK, F_UNLCK, F_WRLCK, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE,
+    FALLOC_FL_ZERO_RANGE, O_ACCMODE, O_APPEND, O_DSYNC, O_RDONLY, O_RDWR, O_SYNC, O_TRUNC,
+    O_WRONLY, R_OK, RENAME_EXCHANGE, RENAME_NOREPLACE, S_IFBLK, S_IFCHR, S_IFIFO, S_IFLNK, S_IFMT,
+    S_IFREG, S_IFSOCK, S_ISGID, SEEK_DATA, SEEK_END, SEEK_HOLE, SEEK_SET, W_OK, X_OK, XATTR_CREATE,
+    XATTR_REPLACE,
+};
 
 pub struct NoctFSFused<'a> {
     fs: NoctFS<'a>,
-    global_fh: u64,
-    fhs_opened: Vec<(u64, u64)>, // (fh, ino)
+    /// Second handle on the image NoctFS writes to, used to sync it.
+    device: FileDevice,
+    handles: HandleTable,
     ino_cache: INOCache,
+    meta: MetaStore,
+    atime_policy: AtimePolicy,
+    /// Mounted `sync`, the default: writes go to NoctFS right away instead of being buffered.
+    sync_writes: bool,
+    /// Size of the image in blocks.
+    total_blocks: u64,
+    /// Blocks only root may fill, so it can still clean up a full image.
+    reserved_blocks: u64,
+    locks: LockManager,
+    /// When the metadata store was last written to the image.
+    meta_saved: Instant,
+    /// Number of subdirectories of each directory whose link count was asked for, kept up
+    /// to date as directories come and go so it doesn't need listing again.
+    subdirectories: HashMap<BlockAddress, u32>,
+    /// Blocks and entities in use, see [`NoctFSFused::usage`].
+    usage: Option<(u64, u64)>,
 }
 
 pub mod device;
 
 impl NoctFSFused<'_> {
-    fn noct_search_by_block(&mut self, block: BlockAddress) -> Option<Entity> {
-        let root = self.fs.get_root_entity();
+    /// Walks the directory `directory_ino` and everything below it, indexing each entry.
+    fn index_directory(&mut self, directory_ino: u64) {
+        let Some(directory_block) = self.ino_cache.find_block(directory_ino) else {
+            return;
+        };
 
-        if let Err(e) = root {
-            eprintln!("noct_search_by_block: {e}");
-            return None;
+        let ents = self.fs.list_directory(directory_block);
+
+        for i in ents {
+            // Hard links share the inode of the file they point to. The orphans directory is
+            // walked like any other, the file a hard link points to may only live there now.
+            if [".", ".."].contains(&i.name.as_str())
+                || self.link_target(&i).is_some()
+                || (i.name != ORPHANS_DIRECTORY_NAME
+                    && self.is_reserved_name(directory_block, &i.name))
+            {
+                continue;
+            }
+
+            let ino = self.assign_ino(directory_ino, &i);
+
+            if i.is_directory() {
+                self.index_directory(ino);
+            }
         }
+    }
 
-        let root = root.unwrap();
+    /// Resolves an inode to its parent directory block and entity, at any depth.
+    ///
+    /// Every operation that moves an entity keeps the inode index in step, so an inode that
+    /// doesn't resolve belongs to an entity that's gone and is dropped from the index.
+    fn resolve(&mut self, ino: u64) -> Option<(BlockAddress, Entity)> {
+        if ino == ROOT_INO {
+            return match self.fs.get_root_entity() {
+                Ok(root) => Some((root.start_block, root)),
+                Err(e) => {
+                    eprintln!("resolve: {e}");
+                    None
+                }
+            };
+        }
 
-        if block == 1 {
-            return Some(root);
+        let block = self.ino_cache.find_block(ino)?;
+
+        let found = self
+            .ino_cache
+            .find_parent(ino)
+            .and_then(|parent| self.ino_cache.find_block(parent))
+            .and_then(|parent_block| {
+                self.fs
+                    .get_entity_by_parent_and_block(parent_block, block)
+                    .map(|entity| (parent_block, entity))
+            });
+
+        if found.is_none() {
+            println!("ino/{ino} is stale, dropping it");
+            self.ino_cache.remove(ino);
         }
 
-        let lsr = self.fs.list_directory(root.start_block);
+        found
+    }
 
-        for i in &lsr {
-            if [".", ".."].contains(&i.name.as_str()) {
-                continue;
-            }
+    fn find_entity(&mut self, ino: u64) -> Option<Entity> {
+        self.resolve(ino).map(|(_, entity)| entity)
+    }
 
-            if i.start_block == block {
-                return Some(i.clone());
-            }
- 
-            if i.is_directory() {
-                return self.noct_search_by_block(i.start_block);
-            }
-       }
+    /// Names that belong to this driver and are never shown to the user.
+    fn is_reserved_name(&self, directory_block: BlockAddress, name: &str) -> bool {
+        [META_FILE_NAME, META_NEW_FILE_NAME, ORPHANS_DIRECTORY_NAME].contains(&name)
+            && Some(directory_block) == self.ino_cache.find_block(ROOT_INO)
+    }
 
-        None
+    /// The `start_block` of the file `entity` is a hard link to, if it's one.
+    fn link_target(&self, entity: &Entity) -> Option<BlockAddress> {
+        self.meta
+            .get(entity.start_block, entity.is_directory())
+            .link_target
     }
 
-    fn search_by_filename<T: ToString>(
-        &mut self,
-        directory_block: BlockAddress,
-        name: T,
-    ) -> Option<Entity> {
-        let ents = self.fs.list_directory(directory_block);
-        let name = name.to_string();
+    /// Returns the inode of the entity starting at `block`, indexing the tree if needed.
+    fn ino_of_block(&mut self, block: BlockAddress) -> Option<u64> {
+        if let Some(ino) = self.ino_cache.find_ino(block) {
+            return Some(ino);
+        }
 
-        for i in ents {
-            if i.name == name {
-                return Some(i.clone());
-            }
+        self.index_directory(ROOT_INO);
+        self.ino_cache.find_ino(block)
+    }
+
+    /// Inode a directory entry stands for: its own, or that of the file it's a hard link to.
+    fn entry_ino(&mut self, directory_ino: u64, entity: &Entity) -> Option<u64> {
+        match self.link_target(entity) {
+            Some(target) => self.ino_of_block(target),
+            None => Some(self.assign_ino(directory_ino, entity)),
         }
+    }
 
-        None
+    /// Returns the inode of `entity`, reusing the number it had on an earlier mount if the
+    /// metadata has one, and recording the number in the metadata otherwise.
+    fn assign_ino(&mut self, parent_ino: u64, entity: &Entity) -> u64 {
+        let block = entity.start_block;
+        let is_directory = entity.is_directory();
+        let stored = self.meta.get(block, is_directory);
+
+        if self.ino_cache.find_ino(block).is_none()
+            && stored.ino != 0
+            && self
+                .ino_cache
+                .insert_known(stored.ino, parent_ino, block, stored.generation)
+        {
+            return stored.ino;
+        }
+
+        let ino = self.ino_cache.add(parent_ino, block);
+        let generation = self.ino_cache.generation(ino);
+
+        if (stored.ino, stored.generation) != (ino, generation) {
+            let meta = self.meta.get_mut(block, is_directory);
+            meta.ino = ino;
+            meta.generation = generation;
+        }
+
+        ino
     }
 
-    fn next_fh(&mut self) -> u64 {
-        let fh = self.global_fh;
+    /// Returns the inode of the hidden directory for files that lost their name but must
+    /// stay alive, creating it on first use.
+    fn orphans_directory(&mut self) -> Option<u64> {
+        let root_block = self.ino_cache.find_block(ROOT_INO)?;
 
-        self.global_fh += 1;
+        let directory = self
+            .fs
+            .list_directory(root_block)
+            .into_iter()
+            .find(|i| i.name == ORPHANS_DIRECTORY_NAME)
+            .unwrap_or_else(|| self.create_entry(root_block, ORPHANS_DIRECTORY_NAME, true));
 
-        fh
+        Some(self.assign_ino(ROOT_INO, &directory))
     }
 
-    fn allocate_fh(&mut self, fh: u64, ino: u64) {
-        println!("Allocated fh: {fh} with ino: {ino}");
-        self.fhs_opened.push((fh, ino));
+    /// Moves `entity` out of `directory_block` into the orphans directory.
+    ///
+    /// The block chain is relinked rather than copied, so however large the file, its data,
+    /// inode and metadata stay exactly where they were.
+    fn orphan_entity(&mut self, directory_block: BlockAddress, entity: &Entity) -> Option<Entity> {
+        let orphans_ino = self.orphans_directory()?;
+        let orphans_block = self.ino_cache.find_block(orphans_ino)?;
+
+        let name = self.free_name(orphans_block, &entity.start_block.to_string());
+
+        self.move_entity(directory_block, entity, orphans_ino, &name)
     }
 
-    fn is_fh_allocated(&self, fh: u64) -> bool {
-        self.fhs_opened.iter().map(|x| x.0).any(|a| a == fh)
+    /// Removes the name `entity` from `directory_block`.
+    ///
+    /// The data is only freed once the last hard link to it is gone and nobody has it open.
+    fn unlink_entity(&mut self, directory_block: BlockAddress, entity: &Entity) -> Option<()> {
+        if let Some(target) = self.link_target(entity) {
+            self.drop_entity(directory_block, entity);
+
+            let ino = self.ino_of_block(target)?;
+            let (target_directory_block, target_entity) = self.resolve(ino)?;
+
+            let meta = self.meta.get_mut(target, false);
+            meta.nlink = meta.nlink.saturating_sub(1);
+            meta.ctime = SystemTime::now();
+
+            // The data already lost its own name, this was the last one. If it's still open,
+            // it stays among the orphans until the last handle is released.
+            if meta.nlink == 0 && !self.is_open(ino) {
+                self.drop_entity(target_directory_block, &target_entity);
+            }
+
+            return Some(());
+        }
+
+        let meta = self.meta.get(entity.start_block, entity.is_directory());
+        let open = self
+            .ino_cache
+            .find_ino(entity.start_block)
+            .is_some_and(|ino| self.is_open(ino));
+
+        // Other hard links and open handles still need the data after the name is gone.
+        if !entity.is_directory() && (meta.nlink > 1 || open) {
+            let orphaned = self.orphan_entity(directory_block, entity)?;
+
+            self.meta.touch_modified(directory_block, true);
+
+            let meta = self.meta.get_mut(orphaned.start_block, false);
+            meta.nlink = meta.nlink.saturating_sub(1);
+            meta.ctime = SystemTime::now();
+
+            return Some(());
+        }
+
+        self.drop_entity(directory_block, entity);
+
+        Some(())
     }
 
-    fn get_ino(&self, fh: u64) -> Option<u64> {
-        if !self.is_fh_allocated(fh) {
-            return None;
+    /// Frees `ino` if it has no names left and the last handle on it is gone.
+    fn reap_orphan(&mut self, ino: u64) {
+        if self.is_open(ino) {
+            return;
+        }
+
+        let Some((directory_block, entity)) = self.resolve(ino) else {
+            return;
+        };
+
+        if entity.is_directory() || self.meta.get(entity.start_block, false).nlink != 0 {
+            return;
         }
 
-        Some(self.fhs_opened.iter().find(|x| x.0 == fh).unwrap().1)
+        println!("Freeing unlinked {ino} after its last release");
+
+        self.drop_entity(directory_block, &entity);
+        self.save_meta();
     }
 
-    fn free_fh(&mut self, fh: u64) {
-        println!("Freeing fh: {fh}");
-        self.fhs_opened.retain(|a| a.0 != fh);
+    /// Frees the orphans a daemon that stopped while they were still open left behind.
+    fn reclaim_orphans(&mut self) {
+        let Some(root_block) = self.ino_cache.find_block(ROOT_INO) else {
+            return;
+        };
+
+        let Some(orphans) = self
+            .fs
+            .list_directory(root_block)
+            .into_iter()
+            .find(|i| i.name == ORPHANS_DIRECTORY_NAME)
+        else {
+            return;
+        };
+
+        for entity in self.fs.list_directory(orphans.start_block) {
+            if entity.is_directory() || self.meta.get(entity.start_block, false).nlink != 0 {
+                continue;
+            }
+
+            println!("Reclaiming orphan {}", entity.name);
+
+            self.drop_entity(orphans.start_block, &entity);
+        }
+
+        self.save_meta();
     }
 
-    fn entity_attrs_to_fuse_attrs(&self, entity: &Entity) -> FileAttr {
-        let no_ts = SystemTime::UNIX_EPOCH;
+    /// Checks whether `req` may read (or, with `write`, change) the attribute `name`.
+    fn check_xattr_access(
+        &self,
+        req: &Request<'_>,
+        entity: &Entity,
+        name: &[u8],
+        write: bool,
+    ) -> Result<(), libc::c_int> {
+        let privileged = req.uid() == 0;
 
-        FileAttr {
-            ino: entity.start_block,
-            size: entity.size,
-            blocks: entity.size * self.fs.block_size() as u64,
-            atime: SystemTime::now(),
-            mtime: no_ts,
-            ctime: no_ts,
-            crtime: no_ts,
-            kind: if entity.is_directory() {
-                FileType::Directory
-            } else {
-                FileType::RegularFile
-            },
-            perm: 0o644,
-            nlink: 0,
-            uid: 0,
-            gid: 0,
-            rdev: 0,
-            flags: 0,
-            blksize: self.fs.block_size() as u32,
+        if name.starts_with(b"user.") {
+            // Permission bits are checked by the kernel, but only files and directories
+            // may carry user attributes.
+            let kind = self.entity_kind(entity);
+
+            if write && ![FileType::RegularFile, FileType::Directory].contains(&kind) {
+                return Err(EPERM);
+            }
+
+            Ok(())
+        } else if name.starts_with(b"trusted.") {
+            match (privileged, write) {
+                (true, _) => Ok(()),
+                (false, true) => Err(EPERM),
+                (false, false) => Err(ENODATA),
+            }
+        } else if name == ACL_ACCESS_XATTR || name == ACL_DEFAULT_XATTR {
+            if !write {
+                return Ok(());
+            }
+
+            if self.entity_kind(entity) == FileType::Symlink {
+                return Err(EOPNOTSUPP);
+            }
+
+            // Only the owner decides who else gets in.
+            let owner = self.meta.get(entity.start_block, entity.is_directory()).uid;
+
+            if !privileged && owner != req.uid() {
+                return Err(EPERM);
+            }
+
+            Ok(())
+        } else if name.starts_with(b"security.") {
+            if write && !privileged {
+                return Err(EPERM);
+            }
+
+            Ok(())
+        } else {
+            Err(EOPNOTSUPP)
         }
     }
-}
 
-const DEFAULT_DURATION: Duration = Duration::from_secs(3600);
+    /// Number of names pointing at `entity`; for directories that's `.`, the name
+    /// in the parent and `..` of every subdirectory.
+    fn entity_nlink(&mut self, entity: &Entity) -> u32 {
+        if !entity.is_directory() {
+            return self.meta.get(entity.start_block, false).nlink;
+        }
 
-impl Filesystem for NoctFSFused<'_> {
-    fn init(
-        &mut self,
-        _req: &Request<'_>,
-        _config: &mut fuser::KernelConfig,
-    ) -> Result<(), libc::c_int> {
-        Ok(())
+        if let Some(&subdirectories) = self.subdirectories.get(&entity.start_block) {
+            return 2 + subdirectories;
+        }
+
+        let subdirectories = self
+            .fs
+            .list_directory(entity.start_block)
+            .iter()
+            .filter(|i| {
+                i.is_directory()
+                    && ![".", ".."].contains(&i.name.as_str())
+                    && !self.is_reserved_name(entity.start_block, &i.name)
+            })
+            .count() as u32;
+
+        self.subdirectories
+            .insert(entity.start_block, subdirectories);
+
+        2 + subdirectories
     }
 
-    fn destroy(&mut self) {}
+    /// Keeps the cached subdirectory count of `directory_block` in step with a directory
+    /// being added to (`delta` 1) or taken out of it (`delta` -1).
+    fn count_subdirectory(&mut self, directory_block: BlockAddress, delta: i32) {
+        if let Some(count) = self.subdirectories.get_mut(&directory_block) {
+            *count = count.saturating_add_signed(delta);
+        }
+    }
 
-    fn lookup(
-        &mut self,
-        _req: &fuser::Request,
-        parent: u64,
-        name: &std::ffi::OsStr,
-        reply: fuser::ReplyEntry,
-    ) {
-        println!("lookup(parent: {:#x?}, name {:?})", parent, name);
+    /// Reads the metadata file from the root directory, if the image has one.
+    fn load_meta(&mut self) {
+        let Some(root_block) = self.ino_cache.find_block(ROOT_INO) else {
+            return;
+        };
 
-        let entity = self.search_by_filename(parent, name.to_str().unwrap());
+        let entries = self.fs.list_directory(root_block);
 
-        if entity.is_none() {
-            println!("lookup failed!");
-            reply.error(ENOENT);
+        // A new copy is only left alone if saving stopped between removing the old store
+        // and giving the new one its name, and by then it was written in full.
+        let file = entries
+            .iter()
+            .find(|i| i.name == META_FILE_NAME)
+            .or_else(|| entries.iter().find(|i| i.name == META_NEW_FILE_NAME));
+
+        let Some(file) = file else {
+            println!("No metadata file, using defaults");
             return;
-        }
+        };
 
-        let entity = entity.unwrap();
-        self.ino_cache.add(parent, entity.start_block);
+        let mut data = vec![0u8; file.size as usize];
 
-        println!("{name:?} is ino {}", entity.start_block);
+        if let Err(e) = self.fs.read_contents_by_entity(file, &mut data, 0) {
+            eprintln!("load_meta: {e:?}");
+            return;
+        }
 
-        reply.entry(
-            &DEFAULT_DURATION,
-            &self.entity_attrs_to_fuse_attrs(&entity),
-            0,
-        );
+        if self.meta.load(&data).is_none() {
+            eprintln!("load_meta: metadata file is damaged, using defaults");
+            return;
+        }
+
+        let (next_ino, free_inos) = self.meta.numbering();
+        self.ino_cache.restore(next_ino, free_inos.to_vec());
     }
 
-    fn forget(&mut self, _req: &fuser::Request, _ino: u64, _nlookup: u64) {}
+    /// Writes the metadata back to the image if anything changed, at most once every
+    /// [`META_SAVE_INTERVAL`] so a burst of changes doesn't rewrite the store for each one.
+    ///
+    /// Whatever is still pending goes out with the next sync or on unmount.
+    fn save_meta(&mut self) {
+        if !self.meta.is_dirty() || self.meta_saved.elapsed() < META_SAVE_INTERVAL {
+            return;
+        }
 
-    fn getattr(
-        &mut self,
-        _req: &fuser::Request,
-        ino: u64,
-        _fh: Option<u64>,
-        reply: fuser::ReplyAttr,
-    ) {
-        println!("getattr on ino/{ino}");
+        if let Err(e) = self.write_meta() {
+            println!("Saving the metadata failed with {e}, keeping it for the next try");
+        }
+    }
 
-        if ino == 1 {
-            reply.attr(
-                &DEFAULT_DURATION,
-                &FileAttr {
-                    ino: ino,
-                    size: 4096,
-                    blocks: 1,
-                    atime: SystemTime::now(),
-                    mtime: SystemTime::UNIX_EPOCH,
-                    ctime: SystemTime::UNIX_EPOCH,
-                    crtime: SystemTime::UNIX_EPOCH,
-                    kind: FileType::Directory,
-                    perm: 0o644,
-                    nlink: 0,
-                    uid: 0,
-                    gid: 0,
-                    rdev: 0,
-                    flags: 0,
-                    blksize: self.fs.block_size() as u32,
-                },
-            );
-        } else {
-            let entity = self.noct_search_by_block(ino);
+    /// Writes the metadata store to the image if anything changed since the last save.
+    ///
+    /// The store is written in full under [`META_NEW_FILE_NAME`] first, and only once that
+    /// is complete does it replace the old one, so a crash leaves one whole copy behind.
+    fn write_meta(&mut self) -> Result<(), libc::c_int> {
+        if !self.meta.is_dirty() {
+            return Ok(());
+        }
 
-            if entity.is_none() {
-                println!("\x1b[31;1mNo entry! ENOENT!\x1b[0m");
+        let root_block = self.ino_cache.find_block(ROOT_INO).ok_or(EIO)?;
 
-                reply.error(ENOENT);
-                return;
+        let entries = self.fs.list_directory(root_block);
+
+        if let Some(stale) = entries.iter().find(|i| i.name == META_NEW_FILE_NAME) {
+            if entries.iter().any(|i| i.name == META_FILE_NAME) {
+                self.delete_entry(root_block, stale);
+            } else {
+                // Saving stopped before the new copy got its name, so it's the only store
+                // there is. It takes the name now and is replaced like any other.
+                let mut renamed = stale.clone();
+                renamed.name = META_FILE_NAME.to_string();
+
+                self.fs
+                    .overwrite_entity_header(root_block, stale, &renamed)
+                    .ok_or(EIO)?;
             }
+        }
+
+        let (next_ino, free_inos) = self.ino_cache.numbering();
+        self.meta.set_numbering(next_ino, &free_inos);
+
+        let data = self.meta.to_bytes();
+        let file = self.create_entry(root_block, META_NEW_FILE_NAME, false);
+
+        self.fs
+            .write_contents_by_entity(root_block, &file, &data, 0);
+
+        let written = self
+            .fs
+            .get_entity_by_parent_and_block(root_block, file.start_block)
+            .unwrap_or(file);
+
+        self.account(Some(0), Some(written.size));
 
-            let entity = entity.unwrap();
-            reply.attr(&DEFAULT_DURATION, &self.entity_attrs_to_fuse_attrs(&entity));
+        // NoctFS doesn't report failed writes, a short file is the only sign of one.
+        if written.size != data.len() as u64 {
+            self.delete_entry(root_block, &written);
+            return Err(EIO);
         }
+
+        if let Some(old) = self
+            .fs
+            .list_directory(root_block)
+            .into_iter()
+            .find(|i| i.name == META_FILE_NAME)
+        {
+            self.delete_entry(root_block, &old);
+        }
+
+        let mut renamed = written.clone();
+        renamed.name = META_FILE_NAME.to_string();
+
+        self.fs
+            .overwrite_entity_header(root_block, &written, &renamed)
+            .ok_or(EIO)?;
+
+        self.meta.mark_clean();
+        self.meta_saved = Instant::now();
+
+        Ok(())
     }
 
-    fn setattr(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        mode: Option<u32>,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
-        _ctime: Option<SystemTime>,
-        fh: Option<u64>,
-        _crtime: Option<SystemTime>,
-        _chgtime: Option<SystemTime>,
-        _bkuptime: Option<SystemTime>,
-        flags: Option<u32>,
-        reply: fuser::ReplyAttr,
-    ) {
-        println!(
-            "setattr(ino: {:#x?}, mode: {:?}, uid: {:?}, \
-            gid: {:?}, size: {:?}, fh: {:?}, flags: {:?})",
-            ino, mode, uid, gid, size, fh, flags
-        );
+    /// Writes out the metadata store and waits until the image is on stable storage.
+    ///
+    /// Buffered writes are left to the caller, so that a failure is only reported for the
+    /// file it belongs to. With `datasync` the metadata store is left alone as well: it only
+    /// holds attributes like timestamps, which aren't needed to read the data back.
+    fn sync_to_disk(&mut self, datasync: bool) -> Result<(), libc::c_int> {
+        if !datasync {
+            self.write_meta().inspect_err(|e| {
+                println!("Saving the metadata failed with {e}");
+            })?;
+        }
 
+        self.device.sync(datasync).map_err(|e| {
+            println!("Syncing the image failed: {e}");
+            e.raw_os_error().unwrap_or(EIO)
+        })
+    }
 
+    /// Builds the metadata of a new entity created by `req` in `directory_block`.
+    fn new_entity_meta(
+        &self,
+        req: &Request<'_>,
+        directory_block: BlockAddress,
+        mode: u32,
+        umask: u32,
+        is_directory: bool,
+    ) -> EntityMeta {
+        let parent = self.meta.get(directory_block, true);
+
+        // A default ACL on the directory takes the place of the umask.
+        let mut mode = match self
+            .meta
+            .xattr(directory_block, ACL_DEFAULT_XATTR)
+            .and_then(Acl::parse)
+        {
+            Some(acl) => (mode & 0o7000) | acl.inherit(mode).mode(),
+            None => mode & !umask & 0o7777,
+        };
+
+        // Directories with the setgid bit pass their group (and, to subdirectories, the bit) on.
+        let gid = if (parent.mode & S_ISGID) != 0 {
+            if is_directory {
+                mode |= S_ISGID;
+            }
 
-        let entity = self.noct_search_by_block(ino);
+            parent.gid
+        } else {
+            req.gid()
+        };
+
+        let now = SystemTime::now();
+
+        EntityMeta {
+            mode,
+            uid: req.uid(),
+            gid,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            nlink: 1,
+            link_target: None,
+            rdev: 0,
+            // Only keeping things out of backups carries over to new entries.
+            flags: parent.flags & FS_NODUMP_FL,
+            ino: 0,
+            generation: 0,
+        }
+    }
 
-        if entity.is_none() {
-            reply.error(ENOENT);
+    /// Gives `entity`, just created in `directory_block` with the requested `mode`,
+    /// the ACLs the directory's default ACL passes on.
+    fn inherit_acls(&mut self, directory_block: BlockAddress, entity: &Entity, mode: u32) {
+        let Some(default) = self
+            .meta
+            .xattr(directory_block, ACL_DEFAULT_XATTR)
+            .map(<[u8]>::to_vec)
+        else {
             return;
+        };
+
+        let Some(acl) = Acl::parse(&default) else {
+            return;
+        };
+
+        let access = acl.inherit(mode);
+
+        if !access.is_minimal() {
+            self.meta.set_xattr(
+                entity.start_block,
+                entity.is_directory(),
+                ACL_ACCESS_XATTR,
+                &access.to_bytes(),
+            );
         }
 
-        let entity = entity.unwrap();
+        if entity.is_directory() {
+            self.meta
+                .set_xattr(entity.start_block, true, ACL_DEFAULT_XATTR, &default);
+        }
+    }
 
-        println!("Found entity: {entity:?}");
+    /// Checks whether the caller may access `entity` as `want` asks,
+    /// a combination of `R_OK`, `W_OK` and `X_OK`.
+    fn check_permission(&self, req: &Request<'_>, entity: &Entity, want: i32) -> bool {
+        let meta = self.meta.get(entity.start_block, entity.is_directory());
+        let want = (want & 0o7) as u16;
 
-        let mut new_entity = entity.clone();
+        if req.uid() == 0 {
+            // Root may do anything, except running a file nobody may run.
+            return (want & X_OK as u16) == 0 || entity.is_directory() || (meta.mode & 0o111) != 0;
+        }
 
-        if let Some(size) = size {
-            println!("Want to trunc to: {}!", size);
+        let acl = self
+            .meta
+            .xattr(entity.start_block, ACL_ACCESS_XATTR)
+            .and_then(Acl::parse)
+            .unwrap_or_else(|| Acl::from_mode(meta.mode));
 
-            if size > entity.size  {
-                println!("TODO! TODO! TODO! Make file bigger! Current size is: {}, setattr wants: {size}", entity.size);
-            }
+        acl.permits(meta.uid, meta.gid, req.uid(), &caller_groups(req), want)
+    }
+
+    /// Attribute flags of `entity` that bind the caller; root isn't held back by any.
+    fn binding_flags(&self, req: &Request<'_>, entity: &Entity) -> u32 {
+        if req.uid() == 0 {
+            return 0;
+        }
 
-            new_entity.size = size;
+        let block = self.link_target(entity).unwrap_or(entity.start_block);
 
-            let parent = self.ino_cache.find_parent(ino);
+        self.meta.get(block, entity.is_directory()).flags
+    }
 
-            if let Some(directory_block) = parent {
-                println!("Writing meta");
+    /// Checks whether the caller may take the name of `entity` out of `directory_block`,
+    /// which neither may be immutable or append-only for.
+    fn may_remove_name(
+        &self,
+        req: &Request<'_>,
+        directory_block: BlockAddress,
+        entity: &Entity,
+    ) -> bool {
+        let protected = FS_IMMUTABLE_FL | FS_APPEND_FL;
+        let directory_flags = if req.uid() == 0 {
+            0
+        } else {
+            self.meta.get(directory_block, true).flags
+        };
 
-                match self.fs.overwrite_entity_header(directory_block, &entity, &new_entity) {
-                    Some(()) => println!("Success!"),
-                    None => println!("Fail!"),
-                }
-            } else {
-                println!("[Error] No parent!");
+        (self.binding_flags(req, entity) & protected) == 0 && (directory_flags & protected) == 0
+    }
+
+    /// Checks whether the caller may add a name to `directory_block`, which it may not be
+    /// immutable for. Append-only directories still take new names.
+    fn may_add_name(&self, req: &Request<'_>, directory_block: BlockAddress) -> bool {
+        req.uid() == 0 || (self.meta.get(directory_block, true).flags & FS_IMMUTABLE_FL) == 0
+    }
+
+    /// Updates the access time of `entity` as the atime policy allows.
+    fn touch_accessed(&mut self, entity: &Entity) {
+        let meta = self.meta.get(entity.start_block, entity.is_directory());
+        let now = SystemTime::now();
+
+        let update = match self.atime_policy {
+            AtimePolicy::NoAtime => false,
+            AtimePolicy::StrictAtime => true,
+            AtimePolicy::RelAtime => {
+                meta.atime <= meta.mtime
+                    || meta.atime <= meta.ctime
+                    || now
+                        .duration_since(meta.atime)
+                        .is_ok_and(|age| age >= Duration::from_secs(24 * 60 * 60))
             }
-        }
+        };
 
-        reply.attr(&DEFAULT_DURATION, &self.entity_attrs_to_fuse_attrs(&new_entity));
+        if update {
+            self.meta
+                .get_mut(entity.start_block, entity.is_directory())
+                .atime = now;
+        }
     }
 
-    fn readlink(&mut self, _req: &fuser::Request, _ino: u64, reply: fuser::ReplyData) {
-        println!("u/i: readlink on ino/{_ino}");
+    /// Deletes `entity` from `directory_block` and forgets everything we kept about it.
+    fn drop_entity(&mut self, directory_block: BlockAddress, entity: &Entity) {
+        self.delete_entry(directory_block, entity);
+        self.handles.forget_entity(entity.start_block);
+        self.meta.touch_modified(directory_block, true);
 
-        reply.error(ENOSYS);
+        if entity.is_directory() {
+            self.count_subdirectory(directory_block, -1);
+            self.subdirectories.remove(&entity.start_block);
+        }
+
+        if let Some(ino) = self.ino_cache.find_ino(entity.start_block) {
+            self.ino_cache.remove(ino);
+        }
+
+        self.meta.remove(entity.start_block);
     }
 
-    fn mknod(
+    fn search_by_filename<T: ToString>(
         &mut self,
-        _req: &fuser::Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        _rdev: u32,
-        reply: fuser::ReplyEntry,
-    ) {
-        println!("u/i: mknod on {parent} with name {name:?}");
+        directory_block: BlockAddress,
+        name: T,
+    ) -> Option<Entity> {
+        let name = name.to_string();
 
-        reply.error(ENOSYS);
+        if self.is_reserved_name(directory_block, &name) {
+            return None;
+        }
+
+        let ents = self.fs.list_directory(directory_block);
+
+        for i in ents {
+            if i.name == name {
+                return Some(i.clone());
+            }
+        }
+
+        None
+    }
+
+    /// Returns `base`, with a counter appended if that name is already taken in `directory_block`.
+    fn free_name(&mut self, directory_block: BlockAddress, base: &str) -> String {
+        let mut name = base.to_string();
+        let mut attempt = 0;
+
+        while self.search_by_filename(directory_block, &name).is_some() {
+            attempt += 1;
+            name = format!("{base}-{attempt}");
+        }
+
+        name
+    }
+
+    fn is_directory_empty(&mut self, directory_block: BlockAddress) -> bool {
+        self.fs
+            .list_directory(directory_block)
+            .iter()
+            .all(|i| [".", ".."].contains(&i.name.as_str()))
+    }
+
+    /// Counts the blocks taken by everything below the directory at `directory_block`,
+    /// and the number of entities there.
+    ///
+    /// NoctFS doesn't expose its allocation map, so the tree is walked instead;
+    /// every entity takes at least one block even when empty.
+    fn usage_below(&mut self, directory_block: BlockAddress) -> (u64, u64) {
+        let block_size = self.fs.block_size() as u64;
+        let (mut blocks, mut files) = (0, 0);
+
+        for entity in self.fs.list_directory(directory_block) {
+            if [".", ".."].contains(&entity.name.as_str()) {
+                continue;
+            }
+
+            blocks += blocks_for(entity.size, block_size);
+            files += 1;
+
+            if entity.is_directory() {
+                let (below_blocks, below_files) = self.usage_below(entity.start_block);
+
+                blocks += below_blocks;
+                files += below_files;
+            }
+        }
+
+        (blocks, files)
+    }
+
+    /// Blocks and entities in use on the image. The tree is only walked the first time, after
+    /// that every entity created, resized or deleted goes through [`NoctFSFused::account`].
+    fn usage(&mut self) -> (u64, u64) {
+        if let Some(usage) = self.usage {
+            return usage;
+        }
+
+        let root_block = self.ino_cache.find_block(ROOT_INO).unwrap();
+        let usage = self.usage_below(root_block);
+
+        self.usage = Some(usage);
+
+        usage
+    }
+
+    /// Keeps the usage figure in step with an entity going from `before` to `after` bytes,
+    /// `None` standing for an entity that doesn't exist.
+    fn account(&mut self, before: Option<u64>, after: Option<u64>) {
+        let block_size = self.fs.block_size() as u64;
+        let blocks = |size: Option<u64>| size.map_or(0, |size| blocks_for(size, block_size));
+        let (added, removed) = (blocks(after), blocks(before));
+
+        if let Some((used, files)) = &mut self.usage {
+            *used = (*used + added).saturating_sub(removed);
+            *files =
+                (*files + u64::from(after.is_some())).saturating_sub(u64::from(before.is_some()));
+        }
+    }
+
+    /// Creates an empty file, or a directory, called `name` in `directory_block`.
+    fn create_entry(
+        &mut self,
+        directory_block: BlockAddress,
+        name: &str,
+        directory: bool,
+    ) -> Entity {
+        let entity = if directory {
+            self.fs.create_directory(directory_block, name)
+        } else {
+            self.fs.create_file(directory_block, name)
+        };
+
+        self.account(None, Some(entity.size));
+
+        entity
+    }
+
+    /// Deletes `entity` from `directory_block`, freeing its blocks.
+    fn delete_entry(&mut self, directory_block: BlockAddress, entity: &Entity) {
+        self.fs.delete_file(directory_block, entity);
+        self.account(Some(entity.size), None);
+    }
+
+    /// Returns the free blocks on the image, and how many of them unprivileged users may take.
+    fn free_blocks(&mut self) -> (u64, u64) {
+        let (used, _) = self.usage();
+
+        // The root directory itself.
+        let free = self.total_blocks.saturating_sub(used + 1);
+
+        (free, free.saturating_sub(self.reserved_blocks))
+    }
+
+    /// Checks whether `ino` is `ancestor` itself or lies somewhere below it.
+    fn is_descendant_of(&self, ino: u64, ancestor: u64) -> bool {
+        let mut current = ino;
+
+        loop {
+            if current == ancestor {
+                return true;
+            }
+
+            if current == ROOT_INO {
+                return false;
+            }
+
+            match self.ino_cache.find_parent(current) {
+                Some(parent) => current = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// Changes the size of `entity`, zero-filling on growth and freeing the tail on shrink.
+    fn resize_entity(
+        &mut self,
+        directory_block: BlockAddress,
+        entity: &Entity,
+        size: u64,
+    ) -> Option<Entity> {
+        self.handles.forget_entity(entity.start_block);
+
+        if size > entity.size {
+            // The gap gets its blocks and is written out as zeros right away, it's only a
+            // hole as far as `SEEK_DATA`/`SEEK_HOLE` go.
+            let grown = self.write_zeros(directory_block, entity, entity.size, size)?;
+            let block_size = self.fs.block_size() as u64;
+
+            self.meta
+                .punch_hole(grown.start_block, entity.size, size, block_size, size);
+
+            return Some(grown);
+        }
+
+        if size == entity.size {
+            return Some(entity.clone());
+        }
+
+        self.shrink_entity(directory_block, entity, size)
+    }
+
+    /// Cuts `entity` down to `size` bytes and frees the blocks past that.
+    ///
+    /// NoctFS can't cut a block chain short, so the part that's kept is copied into a new
+    /// chain under a scratch entry in the orphans directory. The two entries then trade
+    /// headers, the same way [`NoctFSFused::relink_entity`] does, and deleting the scratch
+    /// entry frees the old chain as a whole. Emptying a file copies nothing.
+    fn shrink_entity(
+        &mut self,
+        directory_block: BlockAddress,
+        entity: &Entity,
+        size: u64,
+    ) -> Option<Entity> {
+        let orphans_ino = self.orphans_directory()?;
+        let orphans_block = self.ino_cache.find_block(orphans_ino)?;
+
+        let name = self.free_name(orphans_block, &format!("truncate-{}", entity.start_block));
+        let scratch = self.create_entry(orphans_block, &name, false);
+        let copy = self.copy_head(entity, orphans_block, &scratch, size);
+
+        // NoctFS doesn't report failed writes, a short copy is the only sign of one.
+        if copy.size != size {
+            self.delete_entry(orphans_block, &copy);
+            return None;
+        }
+
+        let mut old = entity.clone();
+        old.name = copy.name.clone();
+
+        if self
+            .fs
+            .overwrite_entity_header(orphans_block, &copy, &old)
+            .is_none()
+        {
+            self.delete_entry(orphans_block, &copy);
+            return None;
+        }
+
+        let mut truncated = copy.clone();
+        truncated.name = entity.name.clone();
+
+        if self
+            .fs
+            .overwrite_entity_header(directory_block, entity, &truncated)
+            .is_none()
+        {
+            // Both entries point at the old chain now, hand the scratch entry the copy back.
+            self.fs.overwrite_entity_header(orphans_block, &old, &copy);
+            self.delete_entry(orphans_block, &copy);
+            return None;
+        }
+
+        self.delete_entry(orphans_block, &old);
+
+        self.meta.rekey(entity.start_block, truncated.start_block);
+        self.meta.truncate_holes(truncated.start_block, size);
+
+        if let Some(ino) = self.ino_cache.find_ino(entity.start_block)
+            && let Some(parent_ino) = self.ino_cache.find_parent(ino)
+        {
+            self.ino_cache
+                .relocate(ino, parent_ino, truncated.start_block);
+        }
+
+        Some(truncated)
+    }
+
+    /// Copies the first `size` bytes of `from` into the empty file `to` and returns the
+    /// header `to` ends up with, which is shorter than `size` if the copy failed.
+    fn copy_head(
+        &mut self,
+        from: &Entity,
+        directory_block: BlockAddress,
+        to: &Entity,
+        size: u64,
+    ) -> Entity {
+        let chunk_size = self.fs.block_size() as u64 * 16;
+        let mut buffer = vec![0u8; chunk_size as usize];
+        let mut current = to.clone();
+        let mut offset = 0;
+
+        while offset < size {
+            let length = (size - offset).min(chunk_size) as usize;
+
+            if self
+                .fs
+                .read_contents_by_entity(from, &mut buffer[..length], offset as _)
+                .is_err()
+            {
+                break;
+            }
+
+            self.fs.write_contents_by_entity(
+                directory_block,
+                &current,
+                &buffer[..length],
+                offset as _,
+            );
+
+            match self
+                .fs
+                .get_entity_by_parent_and_block(directory_block, to.start_block)
+            {
+                Some(written) => current = written,
+                None => break,
+            }
+
+            offset += length as u64;
+        }
+
+        self.account(Some(to.size), Some(current.size));
+
+        current
+    }
+
+    /// Overwrites `from..to` of `entity` with zeros, growing it if `to` lies past the end.
+    fn write_zeros(
+        &mut self,
+        directory_block: BlockAddress,
+        entity: &Entity,
+        from: u64,
+        to: u64,
+    ) -> Option<Entity> {
+        self.handles.forget_entity(entity.start_block);
+
+        let chunk_size = self.fs.block_size() as u64 * 16;
+        let zeros = vec![0u8; chunk_size as usize];
+        let mut offset = from;
+
+        while offset < to {
+            let length = (to - offset).min(chunk_size) as usize;
+
+            let current = self
+                .fs
+                .get_entity_by_parent_and_block(directory_block, entity.start_block)?;
+
+            self.fs.write_contents_by_entity(
+                directory_block,
+                &current,
+                &zeros[..length],
+                offset as _,
+            );
+
+            offset += length as u64;
+        }
+
+        let written = self
+            .fs
+            .get_entity_by_parent_and_block(directory_block, entity.start_block)?;
+
+        self.account(Some(entity.size), Some(written.size));
+
+        Some(written)
+    }
+
+    /// Checks whether the caller may take `blocks` more blocks, leaving the reserved ones to root.
+    fn has_room_for(&mut self, req: &Request<'_>, blocks: u64) -> bool {
+        let (free, available) = self.free_blocks();
+
+        blocks <= if req.uid() == 0 { free } else { available }
+    }
+
+    /// Checks whether the caller may grow a file of `size` bytes to `end` bytes.
+    fn has_room_to_grow(&mut self, req: &Request<'_>, size: u64, end: u64) -> bool {
+        let block_size = self.fs.block_size() as u64;
+        let needed = end
+            .div_ceil(block_size)
+            .saturating_sub(blocks_for(size, block_size));
+
+        self.has_room_for(req, needed)
+    }
+
+    /// Checks whether there's room for the copy of what's kept when a file is cut down to
+    /// `size` bytes, see [`NoctFSFused::shrink_entity`]. The reserved blocks may be used,
+    /// since the file takes fewer blocks than before once it's done.
+    fn has_room_to_shrink(&mut self, size: u64) -> bool {
+        let (free, _) = self.free_blocks();
+
+        blocks_for(size, self.fs.block_size() as u64) <= free
+    }
+
+    /// Moves `entity` out of `directory_block` into `new_directory_ino` under `name`.
+    ///
+    /// Within one directory only the header is rewritten. Across directories the block chain
+    /// is relinked into the destination, so the entity keeps its start block, and with it its
+    /// inode, its metadata and everything below it.
+    fn move_entity(
+        &mut self,
+        directory_block: BlockAddress,
+        entity: &Entity,
+        new_directory_ino: u64,
+        name: &str,
+    ) -> Option<Entity> {
+        let new_directory_block = self.ino_cache.find_block(new_directory_ino)?;
+        let ino = self.ino_cache.find_ino(entity.start_block);
+
+        self.handles.forget_entity(entity.start_block);
+
+        let moved = if directory_block == new_directory_block {
+            let mut renamed = entity.clone();
+            renamed.name = name.to_string();
+
+            self.fs
+                .overwrite_entity_header(directory_block, entity, &renamed)?;
+
+            renamed
+        } else {
+            let moved = self.relink_entity(directory_block, entity, new_directory_block, name)?;
+
+            if moved.is_directory() {
+                self.count_subdirectory(directory_block, -1);
+                self.count_subdirectory(new_directory_block, 1);
+            }
+
+            moved
+        };
+
+        if let Some(ino) = ino {
+            self.ino_cache
+                .relocate(ino, new_directory_ino, moved.start_block);
+        }
+
+        Some(moved)
+    }
+
+    /// Gives the block chain of `entity` a new entry `name` in `new_directory_block` and
+    /// removes the old entry, without copying any data.
+    ///
+    /// NoctFS can only create and delete entries, so a placeholder is created in the
+    /// destination and the two headers trade places. Deleting the old entry then only frees
+    /// the single block the placeholder was given.
+    fn relink_entity(
+        &mut self,
+        directory_block: BlockAddress,
+        entity: &Entity,
+        new_directory_block: BlockAddress,
+        name: &str,
+    ) -> Option<Entity> {
+        let placeholder = self.create_entry(new_directory_block, name, false);
+
+        let mut moved = entity.clone();
+        moved.name = name.to_string();
+
+        if self
+            .fs
+            .overwrite_entity_header(new_directory_block, &placeholder, &moved)
+            .is_none()
+        {
+            self.delete_entry(new_directory_block, &placeholder);
+            return None;
+        }
+
+        let mut left_behind = placeholder.clone();
+        left_behind.name = entity.name.clone();
+
+        if self
+            .fs
+            .overwrite_entity_header(directory_block, entity, &left_behind)
+            .is_none()
+        {
+            // Both entries point at the chain now, hand the placeholder back its own block.
+            self.fs
+                .overwrite_entity_header(new_directory_block, &moved, &placeholder);
+            self.delete_entry(new_directory_block, &placeholder);
+            return None;
+        }
+
+        self.delete_entry(directory_block, &left_behind);
+
+        // A directory's `..` still names the directory it was moved out of.
+        if moved.is_directory()
+            && let Some(dotdot) = self
+                .fs
+                .list_directory(moved.start_block)
+                .into_iter()
+                .find(|i| i.name == ".." && i.start_block == directory_block)
+        {
+            let mut reparented = dotdot.clone();
+            reparented.start_block = new_directory_block;
+
+            self.fs
+                .overwrite_entity_header(moved.start_block, &dotdot, &reparented)?;
+        }
+
+        Some(moved)
+    }
+
+    /// Swaps two directory entries, possibly living in different directories.
+    fn exchange_entities(
+        &mut self,
+        directory_ino: u64,
+        entity: &Entity,
+        new_directory_ino: u64,
+        target: &Entity,
+    ) -> Option<()> {
+        let directory_block = self.ino_cache.find_block(directory_ino)?;
+        let new_directory_block = self.ino_cache.find_block(new_directory_ino)?;
+
+        self.handles.forget_entity(entity.start_block);
+        self.handles.forget_entity(target.start_block);
+
+        if directory_block == new_directory_block {
+            let mut renamed = entity.clone();
+            renamed.name = target.name.clone();
+
+            let mut renamed_target = target.clone();
+            renamed_target.name = entity.name.clone();
+
+            self.fs
+                .overwrite_entity_header(directory_block, entity, &renamed)?;
+
+            if self
+                .fs
+                .overwrite_entity_header(directory_block, target, &renamed_target)
+                .is_none()
+            {
+                self.fs
+                    .overwrite_entity_header(directory_block, &renamed, entity);
+                return None;
+            }
+
+            return Some(());
+        }
+
+        // Park the source under a free name so both names never clash, undoing the steps
+        // already taken if a later one fails.
+        let parked_name = self.free_name(
+            new_directory_block,
+            &format!(".noctfs-exchange-{}", entity.start_block),
+        );
+
+        let parked = self.move_entity(directory_block, entity, new_directory_ino, &parked_name)?;
+
+        let Some(swapped) =
+            self.move_entity(new_directory_block, target, directory_ino, &entity.name)
+        else {
+            self.move_entity(new_directory_block, &parked, directory_ino, &entity.name);
+            return None;
+        };
+
+        if self
+            .move_entity(
+                new_directory_block,
+                &parked,
+                new_directory_ino,
+                &target.name,
+            )
+            .is_none()
+        {
+            self.move_entity(directory_block, &swapped, new_directory_ino, &target.name);
+            self.move_entity(new_directory_block, &parked, directory_ino, &entity.name);
+            return None;
+        }
+
+        Some(())
+    }
+
+    /// Opens a handle on `ino` with the open `flags`.
+    fn open_handle(&mut self, ino: u64, flags: i32) -> u64 {
+        let parent_ino = self.ino_cache.find_parent(ino).unwrap_or(ROOT_INO);
+
+        self.handles
+            .open(ino, parent_ino, flags, (flags & O_APPEND) != 0)
+    }
+
+    /// Whether any handle still refers to `ino`.
+    fn is_open(&self, ino: u64) -> bool {
+        self.handles.is_open(ino)
+    }
+
+    /// Like `resolve`, but reuses the entity the handle `fh` last saw while it's still current.
+    fn handle_entity(&mut self, fh: u64, ino: u64) -> Option<(BlockAddress, Entity)> {
+        let block = self.ino_cache.find_block(ino);
+
+        if let Some(handle) = self.handles.get(fh)
+            && handle.ino == ino
+            && let Some((directory_block, entity)) = &handle.entity
+            && Some(entity.start_block) == block
+        {
+            return Some((*directory_block, entity.clone()));
+        }
+
+        let resolved = self.resolve(ino)?;
+
+        if let Some(handle) = self.handles.get_mut(fh)
+            && handle.ino == ino
+        {
+            handle.entity = Some(resolved.clone());
+        }
+
+        Some(resolved)
+    }
+
+    /// Writes `data` at `offset` of the file `ino`, zero-filling any gap before it.
+    fn write_at(
+        &mut self,
+        directory_block: BlockAddress,
+        entity: &Entity,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<()> {
+        let mut entity = entity.clone();
+
+        // Writing past the end leaves a gap that has to read back as zeros.
+        if offset > entity.size {
+            entity = self.resize_entity(directory_block, &entity, offset)?;
+        }
+
+        self.fs
+            .write_contents_by_entity(directory_block, &entity, data, offset as _);
+
+        // Count what the header says now, a write that failed halfway took fewer blocks.
+        let written = self
+            .fs
+            .get_entity_by_parent_and_block(directory_block, entity.start_block)?;
+
+        self.account(Some(entity.size), Some(written.size));
+        self.meta.fill_holes(
+            entity.start_block,
+            offset,
+            offset + data.len() as u64,
+            self.fs.block_size() as u64,
+        );
+        self.handles.forget_entity(entity.start_block);
+
+        Some(())
+    }
+
+    /// Passes the writes buffered in the handle `fh` on to NoctFS.
+    ///
+    /// If that fails, the data stays in the buffer to be tried again and the error is kept
+    /// for the next write, flush or fsync through the handle to report.
+    fn flush_handle(&mut self, fh: u64) -> Result<(), libc::c_int> {
+        let Some(handle) = self.handles.get_mut(fh) else {
+            return Err(EBADF);
+        };
+
+        if handle.write_buffer.is_empty() {
+            return Ok(());
+        }
+
+        let data = std::mem::take(&mut handle.write_buffer);
+        let (ino, offset) = (handle.ino, handle.write_offset);
+
+        println!("Flushing {} buffered bytes of fh/{fh}", data.len());
+
+        let written = self.resolve(ino).and_then(|(directory_block, entity)| {
+            self.write_at(directory_block, &entity, offset, &data)
+        });
+
+        if written.is_some() {
+            return Ok(());
+        }
+
+        println!("Passing on buffered writes of fh/{fh} to ino/{ino} failed, keeping them");
+
+        if let Some(handle) = self.handles.get_mut(fh) {
+            handle.write_buffer = data;
+            handle.write_error = Some(EIO);
+        }
+
+        Err(EIO)
+    }
+
+    /// Passes every buffered write to `ino` on to NoctFS, so it can be read back or measured.
+    fn flush_writes(&mut self, ino: u64) -> Result<(), libc::c_int> {
+        let mut result = Ok(());
+
+        for fh in self.handles.with_pending_writes(ino) {
+            result = result.and(self.flush_handle(fh));
+        }
+
+        result
+    }
+
+    /// Closes the released handles whose buffered writes went through in the end.
+    fn close_released(&mut self) {
+        for fh in self.handles.released_and_flushed() {
+            if let Some(handle) = self.handles.close(fh) {
+                self.reap_orphan(handle.ino);
+            }
+        }
+    }
+
+    /// Lists the directory `directory_ino` for `readdir`, starting with `.` and `..`.
+    fn list_entries(&mut self, directory_ino: u64, parent_ino: u64) -> Option<Vec<DirectoryEntry>> {
+        let directory_block = self.ino_cache.find_block(directory_ino)?;
+
+        let mut listing = vec![
+            DirectoryEntry {
+                ino: directory_ino,
+                kind: FileType::Directory,
+                name: ".".to_string(),
+            },
+            DirectoryEntry {
+                ino: parent_ino,
+                kind: FileType::Directory,
+                name: "..".to_string(),
+            },
+        ];
+
+        for i in self.fs.list_directory(directory_block) {
+            if i.name == "." || i.name == ".." || self.is_reserved_name(directory_block, &i.name) {
+                continue;
+            }
+
+            let Some(ino) = self.entry_ino(directory_ino, &i) else {
+                continue;
+            };
+
+            let kind = match self.link_target(&i) {
+                Some(_) => self
+                    .find_entity(ino)
+                    .map_or(FileType::RegularFile, |target| self.entity_kind(&target)),
+                None => self.entity_kind(&i),
+            };
+
+            listing.push(DirectoryEntry {
+                ino,
+                kind,
+                name: i.name,
+            });
+        }
+
+        Some(listing)
+    }
+
+    fn entity_kind(&self, entity: &Entity) -> FileType {
+        let meta = self.meta.get(entity.start_block, entity.is_directory());
+
+        match meta.mode & S_IFMT {
+            S_IFLNK => FileType::Symlink,
+            S_IFIFO => FileType::NamedPipe,
+            S_IFSOCK => FileType::Socket,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            _ if entity.is_directory() => FileType::Directory,
+            _ => FileType::RegularFile,
+        }
+    }
+
+    fn entity_attrs_to_fuse_attrs(&mut self, ino: u64, entity: &Entity) -> FileAttr {
+        let block_size = self.fs.block_size() as u64;
+        let meta = self.meta.get(entity.start_block, entity.is_directory());
+
+        FileAttr {
+            ino,
+            size: entity.size,
+            // Holes take up blocks like any other part of the file, so they're counted too.
+            blocks: entity.size.div_ceil(block_size) * block_size / 512,
+            atime: meta.atime,
+            mtime: meta.mtime,
+            ctime: meta.ctime,
+            crtime: meta.crtime,
+            kind: self.entity_kind(entity),
+            perm: (meta.mode & 0o7777) as u16,
+            nlink: self.entity_nlink(entity),
+            uid: meta.uid,
+            gid: meta.gid,
+            rdev: meta.rdev,
+            flags: 0,
+            blksize: self.fs.block_size() as u32,
+        }
+    }
+}
+
+const DEFAULT_DURATION: Duration = Duration::from_secs(3600);
+
+/// Longest name of a single directory entry.
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Longest symlink target.
+const MAX_LINK_LENGTH: usize = 4096;
+
+/// `FS_IOC_GETFLAGS` and `FS_IOC_SETFLAGS` from `linux/fs.h`, as encoded on 64-bit
+/// and 32-bit systems.
+const FS_IOC_GETFLAGS: u32 = 0x8008_6601;
+const FS_IOC_SETFLAGS: u32 = 0x4008_6602;
+const FS_IOC32_GETFLAGS: u32 = 0x8004_6601;
+const FS_IOC32_SETFLAGS: u32 = 0x4004_6602;
+
+/// Share of the image, in percent, kept for root unless `-o reserve=` says otherwise.
+const DEFAULT_RESERVE_PERCENT: u64 = 5;
+
+/// Blocks of sequential writes a handle gathers before passing them on to NoctFS.
+const WRITE_BUFFER_BLOCKS: usize = 16;
+
+/// Largest value of a single extended attribute.
+const MAX_XATTR_VALUE_LENGTH: usize = 65536;
+
+/// Shortest time between two writes of the metadata store that aren't asked for by a sync.
+const META_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Hidden directory in the root that keeps data whose name was removed while still in use.
+const ORPHANS_DIRECTORY_NAME: &str = ".noctfs-orphans";
+
+impl Filesystem for NoctFSFused<'_> {
+    fn init(
+        &mut self,
+        _req: &Request<'_>,
+        _config: &mut fuser::KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        // Have the kernel consult our ACLs when checking permissions.
+        if let Err(unsupported) = _config.add_capabilities(fuser::consts::FUSE_POSIX_ACL) {
+            println!("Kernel doesn't support POSIX ACLs: {unsupported:#x}");
+        }
+
+        // Have the kernel pass `fcntl` locks on instead of keeping them local, so they're
+        // seen by everyone using the image through this daemon. `flock` locks stay with the
+        // kernel, which already ties them to the open file they belong to.
+        if let Err(unsupported) = _config.add_capabilities(fuser::consts::FUSE_POSIX_LOCKS) {
+            println!("Kernel doesn't support passing locks on: {unsupported:#x}");
+        }
+
+        self.load_meta();
+        self.reclaim_orphans();
+
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        for fh in self.handles.all_with_pending_writes() {
+            let _ = self.flush_handle(fh);
+        }
+
+        self.close_released();
+
+        let _ = self.sync_to_disk(false);
+    }
+
+    fn lookup(
+        &mut self,
+        _req: &fuser::Request,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        println!("lookup(parent: {:#x?}, name {:?})", parent, name);
+
+        // NoctFS names are UTF-8, so nothing can be called that.
+        let Some(name) = name.to_str() else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        let entity = self
+            .ino_cache
+            .find_block(parent)
+            .and_then(|directory_block| self.search_by_filename(directory_block, name));
+
+        if entity.is_none() {
+            println!("lookup failed!");
+            reply.error(ENOENT);
+            return;
+        }
+
+        let entity = entity.unwrap();
+
+        let Some(ino) = self.entry_ino(parent, &entity) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        // A hard link reports the attributes of the file it points to.
+        let entity = if self.link_target(&entity).is_some() {
+            match self.find_entity(ino) {
+                Some(target) => target,
+                None => {
+                    reply.error(ENOENT);
+                    return;
+                }
+            }
+        } else {
+            entity
+        };
+
+        println!("{name:?} is ino {ino}");
+
+        // Buffered writes still count towards the size.
+        let entity = if self.handles.with_pending_writes(ino).is_empty() {
+            entity
+        } else if let Err(e) = self.flush_writes(ino) {
+            reply.error(e);
+            return;
+        } else {
+            self.find_entity(ino).unwrap_or(entity)
+        };
+
+        // The number stays taken until the kernel forgets this lookup.
+        self.ino_cache.count_lookup(ino);
+
+        reply.entry(
+            &DEFAULT_DURATION,
+            &self.entity_attrs_to_fuse_attrs(ino, &entity),
+            self.ino_cache.generation(ino),
+        );
+    }
+
+    fn forget(&mut self, _req: &fuser::Request, _ino: u64, _nlookup: u64) {
+        self.ino_cache.forget(_ino, _nlookup);
+    }
+
+    fn getattr(
+        &mut self,
+        _req: &fuser::Request,
+        ino: u64,
+        _fh: Option<u64>,
+        reply: fuser::ReplyAttr,
+    ) {
+        println!("getattr on ino/{ino}");
+
+        // Buffered writes still count towards the size.
+        if let Err(e) = self.flush_writes(ino) {
+            reply.error(e);
+            return;
+        }
+
+        let entity = self.find_entity(ino);
+
+        if entity.is_none() {
+            println!("\x1b[31;1mNo entry! ENOENT!\x1b[0m");
+
+            reply.error(ENOENT);
+            return;
+        }
+
+        let entity = entity.unwrap();
+        reply.attr(
+            &DEFAULT_DURATION,
+            &self.entity_attrs_to_fuse_attrs(ino, &entity),
+        );
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: fuser::ReplyAttr,
+    ) {
+        println!(
+            "setattr(ino: {:#x?}, mode: {:?}, uid: {:?}, \
+            gid: {:?}, size: {:?}, fh: {:?}, flags: {:?})",
+            ino, mode, uid, gid, size, fh, flags
+        );
+
+        if self.flush_writes(ino).is_err() {
+            reply.error(EIO);
+            return;
+        }
+
+        let resolved = self.resolve(ino);
+
+        if resolved.is_none() {
+            reply.error(ENOENT);
+            return;
+        }
+
+        let (directory_block, entity) = resolved.unwrap();
+
+        println!("Found entity: {entity:?}");
+
+        if (self.binding_flags(_req, &entity) & (FS_IMMUTABLE_FL | FS_APPEND_FL)) != 0 {
+            reply.error(EPERM);
+            return;
+        }
+
+        let mut new_entity = entity.clone();
+
+        if let Some(size) = size {
+            println!("Want to resize to: {}!", size);
+
+            if entity.is_directory() {
+                reply.error(EISDIR);
+                return;
+            }
+
+            let room = if size < entity.size {
+                self.has_room_to_shrink(size)
+            } else {
+                self.has_room_to_grow(_req, entity.size, size)
+            };
+
+            if !room {
+                reply.error(ENOSPC);
+                return;
+            }
+
+            match self.resize_entity(directory_block, &entity, size) {
+                Some(resized) => new_entity = resized,
+                None => {
+                    reply.error(EIO);
+                    return;
+                }
+            }
+
+            self.meta
+                .touch_modified(new_entity.start_block, new_entity.is_directory());
+        }
+
+        let changes_meta = mode.is_some()
+            || uid.is_some()
+            || gid.is_some()
+            || atime.is_some()
+            || mtime.is_some()
+            || ctime.is_some()
+            || crtime.is_some();
+
+        if changes_meta {
+            let now = SystemTime::now();
+            let resolve_time = |time: fuser::TimeOrNow| match time {
+                fuser::TimeOrNow::SpecificTime(time) => time,
+                fuser::TimeOrNow::Now => now,
+            };
+
+            let meta = self
+                .meta
+                .get_mut(new_entity.start_block, new_entity.is_directory());
+
+            if let Some(mode) = mode {
+                meta.mode = (meta.mode & S_IFMT) | (mode & 0o7777);
+            }
+
+            if let Some(uid) = uid {
+                meta.uid = uid;
+            }
+
+            if let Some(gid) = gid {
+                meta.gid = gid;
+            }
+
+            if let Some(atime) = atime {
+                meta.atime = resolve_time(atime);
+            }
+
+            if let Some(mtime) = mtime {
+                meta.mtime = resolve_time(mtime);
+            }
+
+            if let Some(crtime) = crtime {
+                meta.crtime = crtime;
+            }
+
+            meta.ctime = ctime.unwrap_or(now);
+
+            // The owner, mask and other entries of the ACL are the permission bits.
+            if let Some(mode) = mode
+                && let Some(mut acl) = self
+                    .meta
+                    .xattr(new_entity.start_block, ACL_ACCESS_XATTR)
+                    .and_then(Acl::parse)
+            {
+                acl.set_mode(mode);
+                self.meta.set_xattr(
+                    new_entity.start_block,
+                    new_entity.is_directory(),
+                    ACL_ACCESS_XATTR,
+                    &acl.to_bytes(),
+                );
+            }
+        }
+
+        self.save_meta();
+
+        reply.attr(
+            &DEFAULT_DURATION,
+            &self.entity_attrs_to_fuse_attrs(ino, &new_entity),
+        );
+    }
+
+    fn readlink(&mut self, _req: &fuser::Request, _ino: u64, reply: fuser::ReplyData) {
+        println!("readlink on ino/{_ino}");
+
+        let Some(entity) = self.find_entity(_ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        if self.entity_kind(&entity) != FileType::Symlink {
+            reply.error(EINVAL);
+            return;
+        }
+
+        let mut target = vec![0u8; entity.size as usize];
+
+        if let Err(e) = self.fs.read_contents_by_entity(&entity, &mut target, 0) {
+            eprintln!("readlink: {e:?}");
+            reply.error(EIO);
+            return;
+        }
+
+        self.touch_accessed(&entity);
+
+        reply.data(&target);
+    }
+
+    fn mknod(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        println!("mknod on {parent} with name {name:?}, mode(o) {_mode:o}, rdev {_rdev:x}");
+
+        let kind = _mode & S_IFMT;
+
+        if ![S_IFREG, S_IFIFO, S_IFSOCK, S_IFCHR, S_IFBLK].contains(&kind) {
+            reply.error(EINVAL);
+            return;
+        }
+
+        // NoctFS can only store UTF-8 names.
+        let Some(name) = name.to_str() else {
+            reply.error(EINVAL);
+            return;
+        };
+
+        if name.len() > MAX_NAME_LENGTH {
+            reply.error(ENAMETOOLONG);
+            return;
+        }
+
+        let Some(directory_block) = self.ino_cache.find_block(parent) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        if self.is_reserved_name(directory_block, name) {
+            reply.error(EEXIST);
+            return;
+        }
+
+        if !self.may_add_name(_req, directory_block) {
+            reply.error(EPERM);
+            return;
+        }
+
+        if !self.has_room_for(_req, 1) {
+            reply.error(ENOSPC);
+            return;
+        }
+
+        let mut meta = self.new_entity_meta(_req, directory_block, _mode, _umask, false);
+
+        // Special files are empty NoctFS files, the kind only lives in metadata.
+        if kind != S_IFREG {
+            meta.mode |= kind;
+            meta.rdev = _rdev;
+        }
+
+        let entity = self.create_entry(directory_block, name, false);
+        self.meta.insert(entity.start_block, meta);
+
+        let ino = self.assign_ino(parent, &entity);
+        self.inherit_acls(directory_block, &entity, _mode);
+        self.meta.touch_modified(directory_block, true);
+        self.save_meta();
+
+        self.ino_cache.count_lookup(ino);
+
+        reply.entry(
+            &DEFAULT_DURATION,
+            &self.entity_attrs_to_fuse_attrs(ino, &entity),
+            self.ino_cache.generation(ino),
+        );
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &fuser::Request,
+        parent: u64,
+        _name: &std::ffi::OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: fuser::ReplyEntry,
+    ) {
+        println!("mkdir on {parent} with name {_name:?}");
+
+        let Some(directory_block) = self.ino_cache.find_block(parent) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        // NoctFS can only store UTF-8 names.
+        let Some(name) = _name.to_str() else {
+            reply.error(EINVAL);
+            return;
+        };
+
+        if self.is_reserved_name(directory_block, name) {
+            reply.error(EEXIST);
+            return;
+        }
+
+        if !self.may_add_name(_req, directory_block) {
+            reply.error(EPERM);
+            return;
+        }
+
+        if !self.has_room_for(_req, 1) {
+            reply.error(ENOSPC);
+            return;
+        }
+
+        let meta = self.new_entity_meta(_req, directory_block, _mode, _umask, true);
+
+        let entity = self.create_entry(directory_block, name, true);
+        self.count_subdirectory(directory_block, 1);
+        self.meta.insert(entity.start_block, meta);
+
+        let ino = self.assign_ino(parent, &entity);
+        self.inherit_acls(directory_block, &entity, _mode);
+        self.meta.touch_modified(directory_block, true);
+        self.save_meta();
+
+        self.ino_cache.count_lookup(ino);
+
+        reply.entry(
+            &DEFAULT_DURATION,
+            &self.entity_attrs_to_fuse_attrs(ino, &entity),
+            self.ino_cache.generation(ino),
+        );
+    }
+
+    fn unlink(
+        &mut self,
+        _req: &fuser::Request,
+        _parent: u64,
+        _name: &std::ffi::OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        println!("u/i: unlink on {_parent} with name {_name:?}");
+
+        let Some(directory_block) = self.ino_cache.find_block(_parent) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        let entity = _name
+            .to_str()
+            .and_then(|name| self.search_by_filename(directory_block, name));
+
+        if entity.is_none() {
+            println!("\x1b[31;1mNo entry! ENOENT!\x1b[0m");
+            reply.error(ENOENT);
+            return;
+        }
+
+        let entity = entity.unwrap();
+
+        if !self.may_remove_name(_req, directory_block, &entity) {
+            reply.error(EPERM);
+            return;
+        }
+
+        let unlinked = self.unlink_entity(directory_block, &entity);
+        self.save_meta();
+
+        match unlinked {
+            Some(()) => reply.ok(),
+            None => reply.error(EIO),
+        }
+    }
+
+    fn rmdir(
+        &mut self,
+        _req: &fuser::Request,
+        _parent: u64,
+        _name: &std::ffi::OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        println!("rmdir on {_parent} with name {_name:?}");
+
+        // NoctFS names are UTF-8, so nothing can be called that.
+        let Some(name) = _name.to_str() else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        if name == "." {
+            reply.error(EINVAL);
+            return;
+        }
+
+        if name == ".." {
+            reply.error(ENOTEMPTY);
+            return;
+        }
+
+        let Some(directory_block) = self.ino_cache.find_block(_parent) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        let Some(entity) = self.search_by_filename(directory_block, name) else {
+            println!("\x1b[31;1mNo entry! ENOENT!\x1b[0m");
+            reply.error(ENOENT);
+            return;
+        };
+
+        if !entity.is_directory() {
+            reply.error(ENOTDIR);
+            return;
+        }
+
+        if !self.is_directory_empty(entity.start_block) {
+            reply.error(ENOTEMPTY);
+            return;
+        }
+
+        self.drop_entity(directory_block, &entity);
+        self.save_meta();
+
+        reply.ok();
     }
 
-    fn mkdir(
-        &mut self,
-        _req: &fuser::Request,
-        parent: u64,
-        _name: &std::ffi::OsStr,
-        _mode: u32,
-        _umask: u32,
-        reply: fuser::ReplyEntry,
-    ) {
-        println!("mkdir on {parent} with name {_name:?}");
+    fn symlink(
+        &mut self,
+        _req: &fuser::Request,
+        _parent: u64,
+        _name: &std::ffi::OsStr,
+        _link: &std::path::Path,
+        reply: fuser::ReplyEntry,
+    ) {
+        println!("symlink on {_parent}, name: {_name:?}, target: {_link:?}");
+
+        // NoctFS can only store UTF-8 names. The target is just contents, so any bytes go.
+        let Some(name) = _name.to_str() else {
+            reply.error(EINVAL);
+            return;
+        };
+
+        let target = _link.as_os_str().as_bytes();
+
+        if name.len() > MAX_NAME_LENGTH || target.len() > MAX_LINK_LENGTH {
+            reply.error(ENAMETOOLONG);
+            return;
+        }
+
+        let Some(directory_block) = self.ino_cache.find_block(_parent) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        if self.is_reserved_name(directory_block, name) {
+            reply.error(EEXIST);
+            return;
+        }
+
+        if !self.may_add_name(_req, directory_block) {
+            reply.error(EPERM);
+            return;
+        }
+
+        let needed = blocks_for(target.len() as u64, self.fs.block_size() as u64);
+
+        if !self.has_room_for(_req, needed) {
+            reply.error(ENOSPC);
+            return;
+        }
+
+        let mut meta = self.new_entity_meta(_req, directory_block, 0o777, 0, false);
+        meta.mode |= S_IFLNK;
+
+        let entity = self.create_entry(directory_block, name, false);
+
+        self.fs
+            .write_contents_by_entity(directory_block, &entity, target, 0);
+
+        let Some(entity) = self
+            .fs
+            .get_entity_by_parent_and_block(directory_block, entity.start_block)
+        else {
+            reply.error(EIO);
+            return;
+        };
+
+        self.account(Some(0), Some(entity.size));
+
+        self.meta.insert(entity.start_block, meta);
+
+        let ino = self.assign_ino(_parent, &entity);
+        self.meta.touch_modified(directory_block, true);
+        self.save_meta();
+
+        self.ino_cache.count_lookup(ino);
+
+        reply.entry(
+            &DEFAULT_DURATION,
+            &self.entity_attrs_to_fuse_attrs(ino, &entity),
+            self.ino_cache.generation(ino),
+        );
+    }
+
+    fn rename(
+        &mut self,
+        _req: &fuser::Request,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        newparent: u64,
+        newname: &std::ffi::OsStr,
+        flags: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        println!(
+            "rename on {parent} with name {name:?}; new parent: {newparent} with new name: {newname:?}, flags: {flags:x}"
+        );
+
+        let exchange = (flags & RENAME_EXCHANGE) != 0;
+        let no_replace = (flags & RENAME_NOREPLACE) != 0;
+
+        if (flags & !(RENAME_EXCHANGE | RENAME_NOREPLACE)) != 0 || (exchange && no_replace) {
+            reply.error(EINVAL);
+            return;
+        }
+
+        // NoctFS names are UTF-8: there's no entry by any other name, and none can be given one.
+        let Some(name) = name.to_str() else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        let Some(newname) = newname.to_str() else {
+            reply.error(EINVAL);
+            return;
+        };
+
+        let (Some(directory_block), Some(new_directory_block)) = (
+            self.ino_cache.find_block(parent),
+            self.ino_cache.find_block(newparent),
+        ) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        // The driver's own files can't be replaced or swapped out.
+        if self.is_reserved_name(new_directory_block, newname) {
+            reply.error(EPERM);
+            return;
+        }
+
+        let Some(entity) = self.search_by_filename(directory_block, name) else {
+            println!("\x1b[31;1mNo entry! ENOENT!\x1b[0m");
+            reply.error(ENOENT);
+            return;
+        };
+
+        let Some(ino) = self.entry_ino(parent, &entity) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        // A directory can't become a child of itself.
+        if entity.is_directory() && self.is_descendant_of(newparent, ino) {
+            reply.error(EINVAL);
+            return;
+        }
+
+        let target = self.search_by_filename(new_directory_block, newname);
+
+        if !self.may_remove_name(_req, directory_block, &entity)
+            || !self.may_add_name(_req, new_directory_block)
+            || target
+                .as_ref()
+                .is_some_and(|target| !self.may_remove_name(_req, new_directory_block, target))
+        {
+            reply.error(EPERM);
+            return;
+        }
+
+        if exchange {
+            let Some(target) = target else {
+                reply.error(ENOENT);
+                return;
+            };
+
+            let Some(target_ino) = self.entry_ino(newparent, &target) else {
+                reply.error(ENOENT);
+                return;
+            };
+
+            if target.is_directory() && self.is_descendant_of(parent, target_ino) {
+                reply.error(EINVAL);
+                return;
+            }
+
+            let exchanged = self.exchange_entities(parent, &entity, newparent, &target);
+
+            self.meta.touch_modified(directory_block, true);
+            self.meta.touch_modified(new_directory_block, true);
+
+            for (swapped_ino, swapped) in [(ino, &entity), (target_ino, &target)] {
+                if let Some(block) = self.ino_cache.find_block(swapped_ino) {
+                    self.meta.touch_changed(block, swapped.is_directory());
+                }
+            }
+
+            self.save_meta();
+
+            match exchanged {
+                Some(()) => reply.ok(),
+                None => reply.error(EIO),
+            }
+
+            return;
+        }
+
+        let mut replaced = None;
+
+        if let Some(target) = target {
+            if no_replace {
+                reply.error(EEXIST);
+                return;
+            }
+
+            // Both names already refer to the same file.
+            if self.entry_ino(newparent, &target) == Some(ino) {
+                reply.ok();
+                return;
+            }
+
+            if target.is_directory() && !entity.is_directory() {
+                reply.error(EISDIR);
+                return;
+            }
+
+            if !target.is_directory() && entity.is_directory() {
+                reply.error(ENOTDIR);
+                return;
+            }
+
+            if target.is_directory() && !self.is_directory_empty(target.start_block) {
+                reply.error(ENOTEMPTY);
+                return;
+            }
+
+            // The target steps aside under a free name and is only dropped once the source
+            // is in its place, so a failed move can hand it its name back.
+            let parked_name = self.free_name(
+                new_directory_block,
+                &format!(".noctfs-rename-{}", target.start_block),
+            );
+
+            match self.move_entity(new_directory_block, &target, newparent, &parked_name) {
+                Some(parked) => replaced = Some((parked, target.name)),
+                None => {
+                    reply.error(EIO);
+                    return;
+                }
+            }
+        }
+
+        let moved = self.move_entity(directory_block, &entity, newparent, newname);
+
+        let moved = match (moved, replaced) {
+            (Some(moved), Some((parked, _))) => self
+                .unlink_entity(new_directory_block, &parked)
+                .map(|()| moved),
+            (None, Some((parked, target_name))) => {
+                self.move_entity(new_directory_block, &parked, newparent, &target_name);
+                None
+            }
+            (moved, None) => moved,
+        };
 
-        let entity = self.fs.create_directory(parent, _name.to_str().unwrap());
+        self.meta.touch_modified(directory_block, true);
+        self.meta.touch_modified(new_directory_block, true);
 
-        reply.entry(
-            &DEFAULT_DURATION,
-            &self.entity_attrs_to_fuse_attrs(&entity),
-            0,
-        );
+        if let Some(moved) = &moved {
+            self.meta
+                .touch_changed(moved.start_block, moved.is_directory());
+        }
+
+        self.save_meta();
 
-        self.ino_cache.add(parent, entity.start_block);
+        match moved {
+            Some(_) => reply.ok(),
+            None => reply.error(EIO),
+        }
     }
 
-    fn unlink(
+    fn link(
         &mut self,
         _req: &fuser::Request,
-        _parent: u64,
-        _name: &std::ffi::OsStr,
-        reply: fuser::ReplyEmpty,
+        _ino: u64,
+        _newparent: u64,
+        _newname: &std::ffi::OsStr,
+        reply: fuser::ReplyEntry,
     ) {
-        println!("u/i: unlink on {_parent} with name {_name:?}");
+        println!("link on ino/{_ino} newparent is: {_newparent}, newname is: {_newname:?}");
 
-        let entity = self.search_by_filename(_parent, _name.to_str().unwrap());
+        // NoctFS can only store UTF-8 names.
+        let Some(name) = _newname.to_str() else {
+            reply.error(EINVAL);
+            return;
+        };
 
-        if entity.is_none() {
-            println!("\x1b[31;1mNo entry! ENOENT!\x1b[0m");
+        if name.len() > MAX_NAME_LENGTH {
+            reply.error(ENAMETOOLONG);
+            return;
+        }
+
+        let Some(entity) = self.find_entity(_ino) else {
             reply.error(ENOENT);
             return;
+        };
+
+        if entity.is_directory()
+            || (self.binding_flags(_req, &entity) & (FS_IMMUTABLE_FL | FS_APPEND_FL)) != 0
+        {
+            reply.error(EPERM);
+            return;
         }
 
-        let entity = entity.unwrap();
+        let Some(directory_block) = self.ino_cache.find_block(_newparent) else {
+            reply.error(ENOENT);
+            return;
+        };
 
-        self.fs.delete_file(_parent, &entity);
+        if self.is_reserved_name(directory_block, name) {
+            reply.error(EEXIST);
+            return;
+        }
 
-        reply.ok();
-    }
+        if self.meta.get(entity.start_block, false).nlink == u32::MAX {
+            reply.error(EMLINK);
+            return;
+        }
 
-    fn rmdir(
-        &mut self,
-        _req: &fuser::Request,
-        _parent: u64,
-        _name: &std::ffi::OsStr,
-        reply: fuser::ReplyEmpty,
-    ) {
-        println!("u/i: rmdir on {_parent} with name {_name:?}");
+        if !self.may_add_name(_req, directory_block) {
+            reply.error(EPERM);
+            return;
+        }
 
-        reply.error(ENOSYS);
-    }
+        if !self.has_room_for(_req, 1) {
+            reply.error(ENOSPC);
+            return;
+        }
 
-    fn symlink(
-        &mut self,
-        _req: &fuser::Request,
-        _parent: u64,
-        _name: &std::ffi::OsStr,
-        _link: &std::path::Path,
-        reply: fuser::ReplyEntry,
-    ) {
-        println!("u/i: symlink on {_parent}, name: {_name:?}");
+        // The new name is an empty entity pointing at the one holding the data.
+        let link = self.create_entry(directory_block, name, false);
 
-        reply.error(ENOSYS);
-    }
+        let mut link_meta = self.meta.get(link.start_block, false);
+        link_meta.link_target = Some(entity.start_block);
+        self.meta.insert(link.start_block, link_meta);
 
-    fn rename(
-        &mut self,
-        _req: &fuser::Request,
-        _parent: u64,
-        _name: &std::ffi::OsStr,
-        _newparent: u64,
-        _newname: &std::ffi::OsStr,
-        _flags: u32,
-        reply: fuser::ReplyEmpty,
-    ) {
-        println!(
-            "u/i: renmae on {_parent} with name {_name:?}; new parent: {_newparent} with new name: {_newname:?}"
-        );
+        let meta = self.meta.get_mut(entity.start_block, false);
+        meta.nlink += 1;
+        meta.ctime = SystemTime::now();
 
-        reply.error(ENOSYS);
-    }
+        self.meta.touch_modified(directory_block, true);
+        self.save_meta();
 
-    fn link(
-        &mut self,
-        _req: &fuser::Request,
-        _ino: u64,
-        _newparent: u64,
-        _newname: &std::ffi::OsStr,
-        reply: fuser::ReplyEntry,
-    ) {
-        println!("u/i: link on ino/{_ino} newparent is: {_newparent}, newname is: {_newname:?}");
+        self.ino_cache.count_lookup(_ino);
 
-        reply.error(ENOSYS);
+        reply.entry(
+            &DEFAULT_DURATION,
+            &self.entity_attrs_to_fuse_attrs(_ino, &entity),
+            self.ino_cache.generation(_ino),
+        );
     }
 
     fn open(&mut self, _req: &fuser::Request, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
@@ -419,16 +2392,59 @@ impl Filesystem for NoctFSFused<'_> {
             }
         );
 
-        // Check for unsupported flags (e.g., O_TRUNC)
-        if (flags & libc::O_TRUNC) != 0 {
-            println!("O_TRUNC not supported!");
-            reply.error(libc::EINVAL); // Or handle truncation
+        let Some((directory_block, entity)) = self.resolve(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        let want = match access_mode {
+            O_RDONLY => R_OK,
+            O_WRONLY => W_OK,
+            _ => R_OK | W_OK,
+        };
+
+        if !self.check_permission(_req, &entity, want) {
+            reply.error(EACCES);
+            return;
+        }
+
+        let attribute_flags = self.binding_flags(_req, &entity);
+        let writes = access_mode != O_RDONLY || (flags & O_TRUNC) != 0;
+
+        // Append-only files may only be written at the end, and never truncated.
+        let refused = if (attribute_flags & FS_IMMUTABLE_FL) != 0 {
+            writes
+        } else if (attribute_flags & FS_APPEND_FL) != 0 {
+            writes && ((flags & O_APPEND) == 0 || (flags & O_TRUNC) != 0)
+        } else {
+            false
+        };
+
+        if refused {
+            reply.error(EPERM);
             return;
         }
 
-        let fh = self.next_fh();
+        if (flags & O_TRUNC) != 0 && access_mode != O_RDONLY {
+            if entity.is_directory() {
+                reply.error(EISDIR);
+                return;
+            }
+
+            if entity.size > 0 && !self.has_room_to_shrink(0) {
+                reply.error(ENOSPC);
+                return;
+            }
+
+            let Some(truncated) = self.resize_entity(directory_block, &entity, 0) else {
+                reply.error(EIO);
+                return;
+            };
+
+            self.meta.touch_modified(truncated.start_block, false);
+        }
 
-        self.allocate_fh(fh, ino);
+        let fh = self.open_handle(ino, flags);
 
         reply.opened(fh, flags.try_into().unwrap());
     }
@@ -445,39 +2461,53 @@ impl Filesystem for NoctFSFused<'_> {
         reply: fuser::ReplyData,
     ) {
         println!("read ino/{ino} fh/{fh}, offset: {offset}, size: {size}");
-        println!("ino from fh is: {:?}", self.get_ino(fh));
-
-        let dir_ino = self.ino_cache.find_parent(ino);
-        println!("ino cache returns: {:?}", dir_ino);
+        println!(
+            "ino from fh is: {:?}",
+            self.handles.get(fh).map(|handle| handle.ino)
+        );
 
-        if dir_ino.is_none() {
-            println!("\x1b[31;1mNo parent directory! EIO!\x1b[0m");
+        if self.flush_writes(ino).is_err() {
             reply.error(EIO);
             return;
         }
 
-        let dir_ino = dir_ino.unwrap();
-        let ent = self.fs.get_entity_by_parent_and_block(dir_ino, ino);
+        let resolved = self.handle_entity(fh, ino);
 
-        if ent.is_none() {
+        if resolved.is_none() {
             // Maybe file is deleted when read is performed idk what to do, let's throw ENOENT then!
             println!("\x1b[31;1mNo entry! ENOENT!\x1b[0m");
             reply.error(ENOENT);
             return;
         }
 
-        let ent = ent.unwrap();
+        let (_, ent) = resolved.unwrap();
 
         println!("Got entity");
 
-        let mut data = vec![0u8; size as usize];
+        let offset = offset.max(0) as u64;
 
-        self.fs
+        // Short read at the end of file, nothing at all past it.
+        if offset >= ent.size {
+            reply.data(&[]);
+            return;
+        }
+
+        let length = (ent.size - offset).min(size as u64);
+        let mut data = vec![0u8; length as usize];
+
+        if let Err(e) = self
+            .fs
             .read_contents_by_entity(&ent, &mut data, offset as _)
-            .unwrap();
+        {
+            eprintln!("read: {e:?}");
+            reply.error(EIO);
+            return;
+        }
 
         println!("Read ok");
 
+        self.touch_accessed(&ent);
+
         reply.data(data.as_slice());
     }
 
@@ -493,33 +2523,103 @@ impl Filesystem for NoctFSFused<'_> {
         _lock_owner: Option<u64>,
         reply: fuser::ReplyWrite,
     ) {
-        println!("\x1b[31mwrite\x1b[0m ino/{ino}; fh/{fh} offset: {offset}, data_size: {}", data.len());
-        println!("ino from fh is: {:?}", self.get_ino(fh));
+        println!(
+            "\x1b[31mwrite\x1b[0m ino/{ino}; fh/{fh} offset: {offset}, data_size: {}",
+            data.len()
+        );
+        println!(
+            "ino from fh is: {:?}",
+            self.handles.get(fh).map(|handle| handle.ino)
+        );
+
+        let Some(handle) = self.handles.get(fh) else {
+            reply.error(EBADF);
+            return;
+        };
 
-        let dir_ino = self.ino_cache.find_parent(ino);
-        println!("ino cache returns: {:?}", dir_ino);
+        let append = handle.append;
 
-        if dir_ino.is_none() {
-            println!("\x1b[31;1mNo parent directory! EIO!\x1b[0m");
-            reply.error(EIO);
+        // Mounted `sync`, or opened for synchronous writes: nothing may wait in a buffer.
+        // The kernel follows each such write with an fsync on its own.
+        let write_through = self.sync_writes || (handle.flags & (O_SYNC | O_DSYNC)) != 0;
+
+        // Buffered writes that couldn't be passed on are reported by the next one.
+        if let Some(e) = self.handles.take_write_error(fh) {
+            reply.error(e);
             return;
         }
 
-        let dir_ino = dir_ino.unwrap();
-        let ent = self.fs.get_entity_by_parent_and_block(dir_ino, ino);
+        // Writes buffered by other handles on the file go first, so they land in order.
+        for other in self.handles.with_pending_writes(ino) {
+            if other != fh && self.flush_handle(other).is_err() {
+                reply.error(EIO);
+                return;
+            }
+        }
+
+        let resolved = self.handle_entity(fh, ino);
 
-        if ent.is_none() {
+        if resolved.is_none() {
             println!("\x1b[31;1mNo entry! ENOENT!\x1b[0m");
             reply.error(ENOENT);
             return;
         }
 
-        let ent = ent.unwrap();
+        let (dir_ino, ent) = resolved.unwrap();
 
         println!("Write on: {}", ent.name);
 
-        self.fs
-            .write_contents_by_entity(dir_ino, &ent, data, offset.try_into().unwrap());
+        let pending_end = self.handles.get(fh).and_then(|handle| handle.pending_end());
+        let size = ent.size.max(pending_end.unwrap_or(0));
+
+        let offset = if append { size } else { offset.max(0) as u64 };
+
+        let attribute_flags = self.binding_flags(_req, &ent);
+
+        if (attribute_flags & FS_IMMUTABLE_FL) != 0
+            || ((attribute_flags & FS_APPEND_FL) != 0 && offset != size)
+        {
+            reply.error(EPERM);
+            return;
+        }
+
+        // Room is claimed when the write is accepted, even if it's only buffered for now.
+        if !self.has_room_to_grow(_req, size, offset + data.len() as u64) {
+            reply.error(ENOSPC);
+            return;
+        }
+
+        // Small sequential writes are gathered and handed to NoctFS in one go.
+        let contiguous = match pending_end {
+            Some(end) => offset == end,
+            None => offset <= ent.size,
+        };
+        let limit = self.fs.block_size() as usize * WRITE_BUFFER_BLOCKS;
+        let handle = self.handles.get_mut(fh).unwrap();
+
+        if !write_through && contiguous && handle.write_buffer.len() + data.len() <= limit {
+            if handle.write_buffer.is_empty() {
+                handle.write_offset = offset;
+            }
+
+            handle.write_buffer.extend_from_slice(data);
+        } else {
+            if self.flush_handle(fh).is_err() {
+                reply.error(self.handles.take_write_error(fh).unwrap_or(EIO));
+                return;
+            }
+
+            let written = self
+                .resolve(ino)
+                .and_then(|(dir_ino, ent)| self.write_at(dir_ino, &ent, offset, data));
+
+            if written.is_none() {
+                reply.error(EIO);
+                return;
+            }
+        }
+
+        self.meta.touch_modified(ent.start_block, false);
 
         reply.written(data.len() as _);
     }
@@ -532,7 +2632,19 @@ impl Filesystem for NoctFSFused<'_> {
         _lock_owner: u64,
         reply: fuser::ReplyEmpty,
     ) {
-        reply.ok();
+        // Closing any descriptor drops the POSIX locks its process held on the file.
+        self.locks.release_owner(_ino, _lock_owner);
+
+        // `close` reports the writes through this descriptor failing, whether now or earlier.
+        // Nothing is synced: that's what `fsync` is for, and the metadata store only goes out
+        // as often as any other change would write it.
+        let flushed = self.flush_handle(_fh);
+        self.save_meta();
+
+        match self.handles.take_write_error(_fh).map_or(flushed, Err) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
     }
 
     fn release(
@@ -545,6 +2657,20 @@ impl Filesystem for NoctFSFused<'_> {
         _flush: bool,
         reply: fuser::ReplyEmpty,
     ) {
+        // Data that still can't be passed on keeps the handle around, so the next sync can
+        // try again. `close` already got the error from `flush`.
+        if let Err(e) = self.flush_handle(_fh) {
+            if let Some(handle) = self.handles.get_mut(_fh) {
+                handle.released = true;
+            }
+
+            reply.error(e);
+            return;
+        }
+
+        self.handles.close(_fh);
+        self.reap_orphan(_ino);
+
         reply.ok();
     }
 
@@ -556,43 +2682,56 @@ impl Filesystem for NoctFSFused<'_> {
         _datasync: bool,
         reply: fuser::ReplyEmpty,
     ) {
-        println!("fsync");
+        println!("fsync(ino: {_ino}, fh: {_fh}, datasync: {_datasync})");
 
-        reply.ok();
+        // Only this file's buffered writes go out, other files' failures are theirs to report.
+        let flushed = self.flush_writes(_ino);
+        self.close_released();
+
+        let synced = flushed.and_then(|()| self.sync_to_disk(_datasync));
+
+        match self.handles.take_write_error(_fh).map_or(synced, Err) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
     }
 
     fn opendir(&mut self, _req: &fuser::Request, _ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
         println!("opendir {_ino} {_flags}");
 
-        if _ino == 1 {
-            let fh = self.next_fh();
+        if _ino != ROOT_INO {
+            println!("== Other dir!");
+            let ent = self.find_entity(_ino);
+            if ent.is_none() {
+                println!("\x1b[31;1mNo entry! ENOENT!\x1b[0m");
 
-            self.allocate_fh(fh, 1);
+                reply.error(ENOENT);
+                return;
+            }
+            let ent = ent.unwrap();
 
-            reply.opened(fh, _flags.try_into().unwrap());
+            if !ent.is_directory() {
+                println!("\x1b[31;1mIs not a directory! ENOENT!\x1b[0m");
 
-            return;
+                reply.error(ENOENT);
+                return;
+            }
         }
 
-        println!("== Other dir!");
-        let ent = self.noct_search_by_block(_ino);
-        if ent.is_none() {
-            println!("\x1b[31;1mNo entry! ENOENT!\x1b[0m");
+        let fh = self.open_handle(_ino, _flags);
+        let parent_ino = self.handles.get(fh).unwrap().parent_ino;
 
+        // The listing is taken once, so entries added or removed while it's read through
+        // neither show up twice nor shift the offsets handed out for the rest.
+        let Some(listing) = self.list_entries(_ino, parent_ino) else {
+            self.handles.close(fh);
             reply.error(ENOENT);
             return;
-        }
-        let ent = ent.unwrap();
-
-        if !ent.is_directory() {
-            println!("\x1b[31;1mIs not a directory! ENOENT!\x1b[0m");
+        };
 
-            reply.error(ENOENT);
-            return;
-        }
+        println!("Listed {} entries for fh/{fh}", listing.len());
 
-        let fh = self.next_fh();
-        self.allocate_fh(fh, _ino);
+        self.handles.get_mut(fh).unwrap().directory = Some(listing);
 
         reply.opened(fh, _flags.try_into().unwrap());
 
@@ -609,35 +2748,50 @@ impl Filesystem for NoctFSFused<'_> {
     ) {
         println!("readdir {_ino} {_fh} {_offset}");
 
-        if !self.is_fh_allocated(_fh) {
-            reply.ok();
+        let Some(handle) = self.handles.get(_fh) else {
+            reply.error(EBADF);
             return;
-        }
+        };
+
+        // Starting over once entries were handed out is a `rewinddir`, which has to see the
+        // directory as it is now, `..` included if it was moved meanwhile.
+        let rewind = _offset == 0 && handle.directory_read;
+
+        if handle.directory.is_none() || rewind {
+            let parent_ino = if rewind {
+                self.ino_cache.find_parent(_ino).unwrap_or(ROOT_INO)
+            } else {
+                handle.parent_ino
+            };
 
-        let ents = self.fs.list_directory(_ino);
+            let Some(listing) = self.list_entries(_ino, parent_ino) else {
+                reply.error(ENOENT);
+                return;
+            };
 
-        // println!("{ents:#?}");
+            let handle = self.handles.get_mut(_fh).unwrap();
+            handle.directory = Some(listing);
+            handle.parent_ino = parent_ino;
+        }
 
+        if _offset == 0
+            && let Some(directory) = self.find_entity(_ino)
         {
-            // let parent_dir = self.ino_cache.find_parent(_ino);
-            // reply.add()
+            self.touch_accessed(&directory);
         }
 
-        for i in ents {
-            let result: bool = reply.add(
-                i.start_block,
-                0,
-                if i.is_directory() {
-                    FileType::Directory
-                } else {
-                    FileType::RegularFile
-                },
-                i.name,
-            );
+        let listing = self.handles.get(_fh).unwrap().directory.as_ref().unwrap();
+
+        // Each entry's offset is where the next call picks up after it.
+        for (i, entry) in listing.iter().enumerate().skip(_offset.max(0) as usize) {
+            if reply.add(entry.ino, i as i64 + 1, entry.kind, &entry.name) {
+                break;
+            }
         }
-        reply.ok();
 
-        self.free_fh(_fh);
+        self.handles.get_mut(_fh).unwrap().directory_read = true;
+
+        reply.ok();
     }
 
     fn readdirplus(
@@ -663,36 +2817,137 @@ impl Filesystem for NoctFSFused<'_> {
         _flags: i32,
         reply: fuser::ReplyEmpty,
     ) {
+        println!("releasedir(ino: {_ino}, fh: {_fh})");
+
+        self.handles.close(_fh);
+
         reply.ok();
     }
 
-    fn fsyncdir(
-        &mut self,
-        _req: &fuser::Request,
-        _ino: u64,
-        _fh: u64,
-        _datasync: bool,
-        reply: fuser::ReplyEmpty,
-    ) {
-        reply.ok();
-    }
+    fn fsyncdir(
+        &mut self,
+        _req: &fuser::Request,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        println!("fsyncdir(ino: {_ino}, fh: {_fh}, datasync: {_datasync})");
+
+        match self.sync_to_disk(_datasync) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn statfs(&mut self, _req: &fuser::Request, _ino: u64, reply: fuser::ReplyStatfs) {
+        println!("statfs(ino: {:#x?})", _ino);
+
+        let (_, files) = self.usage();
+        let (free, available) = self.free_blocks();
+        let block_size = self.fs.block_size() as u32;
+
+        // Every entity needs a block of its own, so there is room for one per free block.
+        reply.statfs(
+            self.total_blocks,
+            free,
+            available,
+            files + 1 + free,
+            free,
+            block_size,
+            MAX_NAME_LENGTH as u32,
+            block_size,
+        );
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &fuser::Request,
+        _ino: u64,
+        _name: &std::ffi::OsStr,
+        _value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        println!("setxattr on {_ino} with name {_name:?}, flags: {_flags:x}");
+
+        let name = _name.as_bytes();
+
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+            reply.error(ERANGE);
+            return;
+        }
+
+        if _value.len() > MAX_XATTR_VALUE_LENGTH {
+            reply.error(E2BIG);
+            return;
+        }
+
+        let Some(entity) = self.find_entity(_ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        if let Err(e) = self.check_xattr_access(_req, &entity, name, true) {
+            reply.error(e);
+            return;
+        }
+
+        let exists = self.meta.xattr(entity.start_block, name).is_some();
+
+        if (_flags & XATTR_CREATE) != 0 && exists {
+            reply.error(EEXIST);
+            return;
+        }
+
+        if (_flags & XATTR_REPLACE) != 0 && !exists {
+            reply.error(ENODATA);
+            return;
+        }
+
+        if !self.meta.xattr_fits(entity.start_block, name, _value.len()) {
+            reply.error(ENOSPC);
+            return;
+        }
+
+        let value = if name == ACL_ACCESS_XATTR || name == ACL_DEFAULT_XATTR {
+            let Some(acl) = Acl::parse(_value) else {
+                reply.error(EINVAL);
+                return;
+            };
+
+            if name == ACL_ACCESS_XATTR {
+                let meta = self.meta.get_mut(entity.start_block, entity.is_directory());
+                meta.mode = (meta.mode & !0o777) | acl.mode();
+
+                // The permission bits alone already say what a minimal ACL would.
+                (!acl.is_minimal()).then(|| acl.to_bytes())
+            } else if entity.is_directory() {
+                Some(acl.to_bytes())
+            } else {
+                reply.error(EACCES);
+                return;
+            }
+        } else {
+            Some(_value.to_vec())
+        };
+
+        match value {
+            Some(value) => {
+                self.meta
+                    .set_xattr(entity.start_block, entity.is_directory(), name, &value)
+            }
+            None => {
+                self.meta.remove_xattr(entity.start_block, name);
+            }
+        }
 
-    fn statfs(&mut self, _req: &fuser::Request, _ino: u64, reply: fuser::ReplyStatfs) {
-        reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
-    }
+        self.meta
+            .touch_changed(entity.start_block, entity.is_directory());
+        self.save_meta();
 
-    fn setxattr(
-        &mut self,
-        _req: &fuser::Request,
-        _ino: u64,
-        _name: &std::ffi::OsStr,
-        _value: &[u8],
-        _flags: i32,
-        _position: u32,
-        reply: fuser::ReplyEmpty,
-    ) {
-        println!("u/i: setxattr on {_ino} with name {_name:?}");
-        reply.error(ENOSYS);
+        reply.ok();
     }
 
     fn access(&mut self, _req: &fuser::Request, _ino: u64, _mask: i32, reply: fuser::ReplyEmpty) {
@@ -702,11 +2957,16 @@ impl Filesystem for NoctFSFused<'_> {
         println!("Parent: {parent:?}");
 
         // Search inode across entire FS (may be slow, but idk what to do without parent ino)
-        let a = self.noct_search_by_block(_ino);
-        if a.is_none() {
+        let Some(entity) = self.find_entity(_ino) else {
             println!("access failed!");
             reply.error(ENOENT);
             return;
+        };
+
+        if _mask != F_OK && !self.check_permission(_req, &entity, _mask) {
+            println!("access denied");
+            reply.error(EACCES);
+            return;
         }
 
         println!("access succeeded");
@@ -725,17 +2985,50 @@ impl Filesystem for NoctFSFused<'_> {
     ) {
         println!("Create {name:?} on ino/{parent} with mode(o) {mode:o} and flags(x) {flags:x}");
 
-        let entity = self.fs.create_file(parent, name.to_str().unwrap());
+        let Some(directory_block) = self.ino_cache.find_block(parent) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        // NoctFS can only store UTF-8 names.
+        let Some(name) = name.to_str() else {
+            reply.error(EINVAL);
+            return;
+        };
+
+        if self.is_reserved_name(directory_block, name) {
+            reply.error(EEXIST);
+            return;
+        }
+
+        if !self.may_add_name(_req, directory_block) {
+            reply.error(EPERM);
+            return;
+        }
+
+        if !self.has_room_for(_req, 1) {
+            reply.error(ENOSPC);
+            return;
+        }
+
+        let meta = self.new_entity_meta(_req, directory_block, mode, _umask, false);
+
+        let entity = self.create_entry(directory_block, name, false);
+        self.meta.insert(entity.start_block, meta);
+
+        let ino = self.assign_ino(parent, &entity);
+        self.inherit_acls(directory_block, &entity, mode);
+        self.meta.touch_modified(directory_block, true);
+        self.save_meta();
 
-        let fh = self.next_fh();
-        self.allocate_fh(fh, entity.start_block);
+        let fh = self.open_handle(ino, flags);
 
-        self.ino_cache.add(parent, entity.start_block);
+        self.ino_cache.count_lookup(ino);
 
         reply.created(
             &DEFAULT_DURATION,
-            &self.entity_attrs_to_fuse_attrs(&entity),
-            0,
+            &self.entity_attrs_to_fuse_attrs(ino, &entity),
+            self.ino_cache.generation(ino),
             fh,
             flags as u32 & 0b111,
             // O_RDWR.try_into().unwrap(),
@@ -754,9 +3047,22 @@ impl Filesystem for NoctFSFused<'_> {
         _pid: u32,
         reply: fuser::ReplyLock,
     ) {
-        println!("u/i: getlk on {_ino} with fh {_fh}");
+        println!(
+            "getlk on {_ino} with fh {_fh}, owner {_lock_owner:#x}, range {_start}..={_end}, type {_typ}"
+        );
 
-        reply.error(ENOSYS);
+        let lock = Lock {
+            owner: _lock_owner,
+            pid: _pid,
+            start: _start,
+            end: _end,
+            typ: _typ,
+        };
+
+        match self.locks.conflict(_ino, &lock) {
+            Some(held) => reply.locked(held.start, held.end, held.typ, held.pid),
+            None => reply.locked(_start, _end, F_UNLCK, 0),
+        }
     }
 
     fn setlk(
@@ -772,9 +3078,40 @@ impl Filesystem for NoctFSFused<'_> {
         _sleep: bool,
         reply: fuser::ReplyEmpty,
     ) {
-        println!("u/i: setlk on {_ino} with fh {_fh}");
+        println!(
+            "setlk on {_ino} with fh {_fh}, owner {_lock_owner:#x}, range {_start}..={_end}, \
+            type {_typ}, sleep: {_sleep}"
+        );
 
-        reply.error(ENOSYS);
+        if ![F_RDLCK, F_WRLCK, F_UNLCK].contains(&_typ) || _start > _end {
+            reply.error(EINVAL);
+            return;
+        }
+
+        let lock = Lock {
+            owner: _lock_owner,
+            pid: _pid,
+            start: _start,
+            end: _end,
+            typ: _typ,
+        };
+
+        if _typ != F_UNLCK && self.locks.conflict(_ino, &lock).is_some() {
+            if _sleep && self.locks.would_deadlock(_ino, &lock) {
+                reply.error(EDEADLK);
+            } else if _sleep {
+                // Answered once the conflicting locks are released.
+                self.locks.wait(_ino, lock, reply);
+            } else {
+                reply.error(EAGAIN);
+            }
+
+            return;
+        }
+
+        self.locks.set(_ino, lock);
+
+        reply.ok();
     }
 
     fn bmap(
@@ -801,8 +3138,7 @@ impl Filesystem for NoctFSFused<'_> {
         reply: fuser::ReplyIoctl,
     ) {
         println!(
-            "[Not Implemented] ioctl(ino: {:#x?}, fh: {}, flags: {}, cmd: {}, \
-            in_data.len(): {}, out_size: {})",
+            "ioctl(ino: {:#x?}, fh: {}, flags: {}, cmd: {:#x}, in_data.len(): {}, out_size: {})",
             ino,
             fh,
             flags,
@@ -810,7 +3146,62 @@ impl Filesystem for NoctFSFused<'_> {
             in_data.len(),
             out_size,
         );
-        reply.error(ENOSYS);
+
+        let Some(entity) = self.find_entity(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        match cmd {
+            FS_IOC_GETFLAGS | FS_IOC32_GETFLAGS => {
+                if out_size < 4 {
+                    reply.error(EINVAL);
+                    return;
+                }
+
+                // Callers pass either an `int` or a `long`, so the value is padded to fit.
+                let mut data = self
+                    .meta
+                    .get(entity.start_block, entity.is_directory())
+                    .flags
+                    .to_le_bytes()
+                    .to_vec();
+                data.resize(out_size.min(8) as usize, 0);
+
+                reply.ioctl(0, &data);
+            }
+            FS_IOC_SETFLAGS | FS_IOC32_SETFLAGS => {
+                let Some(value) = in_data.first_chunk::<4>() else {
+                    reply.error(EINVAL);
+                    return;
+                };
+
+                let new_flags = u32::from_le_bytes(*value);
+
+                if (new_flags & !(FS_IMMUTABLE_FL | FS_APPEND_FL | FS_NODUMP_FL)) != 0 {
+                    reply.error(EOPNOTSUPP);
+                    return;
+                }
+
+                let privileged = _req.uid() == 0;
+                let meta = self.meta.get_mut(entity.start_block, entity.is_directory());
+
+                // Only root may set or clear the flags that lock a file down.
+                let locking = (meta.flags ^ new_flags) & (FS_IMMUTABLE_FL | FS_APPEND_FL);
+
+                if !privileged && (meta.uid != _req.uid() || locking != 0) {
+                    reply.error(EPERM);
+                    return;
+                }
+
+                meta.flags = new_flags;
+                meta.ctime = SystemTime::now();
+                self.save_meta();
+
+                reply.ioctl(0, &[]);
+            }
+            _ => reply.error(ENOTTY),
+        }
     }
 
     fn fallocate(
@@ -824,11 +3215,101 @@ impl Filesystem for NoctFSFused<'_> {
         reply: fuser::ReplyEmpty,
     ) {
         println!(
-            "[Not Implemented] fallocate(ino: {:#x?}, fh: {}, offset: {}, \
-            length: {}, mode: {})",
+            "fallocate(ino: {:#x?}, fh: {}, offset: {}, length: {}, mode: {:#x})",
             ino, fh, offset, length, mode
         );
-        reply.error(ENOSYS);
+
+        let keep_size = (mode & FALLOC_FL_KEEP_SIZE) != 0;
+        let punch_hole = (mode & FALLOC_FL_PUNCH_HOLE) != 0;
+        let zero_range = (mode & FALLOC_FL_ZERO_RANGE) != 0;
+
+        // Collapsing and inserting ranges would mean moving data around inside block chains.
+        let supported = FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE;
+
+        if (mode & !supported) != 0 || (punch_hole && (zero_range || !keep_size)) {
+            reply.error(EOPNOTSUPP);
+            return;
+        }
+
+        if offset < 0 || length <= 0 {
+            reply.error(EINVAL);
+            return;
+        }
+
+        if self.flush_writes(ino).is_err() {
+            reply.error(EIO);
+            return;
+        }
+
+        let Some((directory_block, entity)) = self.resolve(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        if entity.is_directory() {
+            reply.error(EISDIR);
+            return;
+        }
+
+        // Append-only files may still have room reserved, as long as nothing is overwritten.
+        let attribute_flags = self.binding_flags(_req, &entity);
+
+        if (attribute_flags & FS_IMMUTABLE_FL) != 0
+            || ((attribute_flags & FS_APPEND_FL) != 0 && (mode & !FALLOC_FL_KEEP_SIZE) != 0)
+        {
+            reply.error(EPERM);
+            return;
+        }
+
+        let (start, end) = (offset as u64, offset as u64 + length as u64);
+        let block_size = self.fs.block_size() as u64;
+
+        let result = if punch_hole {
+            // NoctFS can't free blocks in the middle of a chain, so the hole keeps its blocks
+            // and just reads back as zeros.
+            if start < entity.size {
+                let end = end.min(entity.size);
+
+                self.meta
+                    .punch_hole(entity.start_block, start, end, block_size, entity.size);
+                self.write_zeros(directory_block, &entity, start, end)
+            } else {
+                Some(entity)
+            }
+        } else {
+            if !self.has_room_to_grow(_req, entity.size, end) {
+                reply.error(ENOSPC);
+                return;
+            }
+
+            // Blocks past the end can't be held without growing the file, so preallocating
+            // with `FALLOC_FL_KEEP_SIZE` only makes sure there's room for them right now.
+            let end = if keep_size { end.min(entity.size) } else { end };
+
+            if zero_range {
+                self.write_zeros(directory_block, &entity, start.min(entity.size), end)
+            } else if end > entity.size {
+                let grown = self.resize_entity(directory_block, &entity, end);
+
+                // Preallocated blocks are meant to be written to, they aren't holes.
+                self.meta
+                    .fill_holes(entity.start_block, entity.size, end, block_size);
+
+                grown
+            } else {
+                // Everything up to the end already has its blocks.
+                Some(entity)
+            }
+        };
+
+        let Some(entity) = result else {
+            reply.error(EIO);
+            return;
+        };
+
+        self.meta.touch_modified(entity.start_block, false);
+
+        reply.ok();
     }
 
     fn lseek(
@@ -841,10 +3322,48 @@ impl Filesystem for NoctFSFused<'_> {
         reply: fuser::ReplyLseek,
     ) {
         println!(
-            "[Not Implemented] lseek(ino: {:#x?}, fh: {}, offset: {}, whence: {})",
+            "lseek(ino: {:#x?}, fh: {}, offset: {}, whence: {})",
             ino, fh, offset, whence
         );
-        reply.error(ENOSYS);
+
+        if let Err(e) = self.flush_writes(ino) {
+            reply.error(e);
+            return;
+        }
+
+        let Some(entity) = self.find_entity(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        let size = entity.size;
+        let holes = self
+            .meta
+            .holes(entity.start_block)
+            .cloned()
+            .unwrap_or_default();
+
+        let position = match whence {
+            SEEK_SET => Some(offset),
+            SEEK_END => Some(size as i64 + offset),
+            SEEK_DATA | SEEK_HOLE if offset < 0 => {
+                reply.error(ENXIO);
+                return;
+            }
+            SEEK_DATA => holes.next_data(offset as u64, size).map(|data| data as i64),
+            SEEK_HOLE => holes.next_hole(offset as u64, size).map(|hole| hole as i64),
+            _ => {
+                // The kernel keeps track of the current position itself.
+                reply.error(EINVAL);
+                return;
+            }
+        };
+
+        match position {
+            Some(position) if position >= 0 => reply.offset(position),
+            Some(_) => reply.error(EINVAL),
+            None => reply.error(ENXIO),
+        }
     }
 
     fn copy_file_range(
@@ -861,12 +3380,145 @@ impl Filesystem for NoctFSFused<'_> {
         reply: fuser::ReplyWrite,
     ) {
         println!(
-            "[Not Implemented] copy_file_range(ino_in: {:#x?}, fh_in: {}, \
-            offset_in: {}, ino_out: {:#x?}, fh_out: {}, offset_out: {}, \
-            len: {}, flags: {})",
+            "copy_file_range(ino_in: {:#x?}, fh_in: {}, offset_in: {}, ino_out: {:#x?}, \
+            fh_out: {}, offset_out: {}, len: {}, flags: {})",
             ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags
         );
-        reply.error(ENOSYS);
+
+        if flags != 0 || offset_in < 0 || offset_out < 0 {
+            reply.error(EINVAL);
+            return;
+        }
+
+        if self.handles.get(fh_out).is_some_and(|handle| handle.append) {
+            reply.error(EBADF);
+            return;
+        }
+
+        if self.flush_writes(ino_in).is_err() || self.flush_writes(ino_out).is_err() {
+            reply.error(EIO);
+            return;
+        }
+
+        let Some((_, source)) = self.resolve(ino_in) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        let Some((directory_block, mut destination)) = self.resolve(ino_out) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        if source.is_directory() || destination.is_directory() {
+            reply.error(EISDIR);
+            return;
+        }
+
+        if (self.binding_flags(_req, &destination) & (FS_IMMUTABLE_FL | FS_APPEND_FL)) != 0 {
+            reply.error(EPERM);
+            return;
+        }
+
+        let (offset_in, offset_out) = (offset_in as u64, offset_out as u64);
+
+        // Copies stop short at the end of the source, like reads do.
+        let length = len
+            .min(source.size.saturating_sub(offset_in))
+            .min(u32::MAX as u64);
+
+        if length == 0 {
+            reply.written(0);
+            return;
+        }
+
+        let block_size = self.fs.block_size() as u64;
+        let end = offset_out + length;
+
+        if !self.has_room_to_grow(_req, destination.size, end) {
+            reply.error(ENOSPC);
+            return;
+        }
+
+        if offset_out > destination.size {
+            match self.resize_entity(directory_block, &destination, offset_out) {
+                Some(resized) => destination = resized,
+                None => {
+                    reply.error(EIO);
+                    return;
+                }
+            }
+        }
+
+        let same_file = ino_in == ino_out;
+
+        // Within one file, copying forward over a later part of the source would overwrite it
+        // before it's read, so such copies go back to front.
+        let backwards = same_file && offset_out > offset_in && offset_out < offset_in + length;
+
+        // NoctFS has no reference counts on blocks, so ranges can't be shared between entities
+        // and are always copied. For the same reason clones (`FICLONE`, `cp --reflink`) aren't
+        // supported; the kernel reports that on its own since FUSE has no request for them.
+        let chunk_size = block_size * 16;
+        let mut buffer = vec![];
+        let mut done = 0u64;
+
+        while done < length {
+            let chunk = (length - done).min(chunk_size);
+            let position = if backwards {
+                length - done - chunk
+            } else {
+                done
+            };
+
+            buffer.resize(chunk as usize, 0);
+
+            // Both headers change as the destination grows, so take fresh copies.
+            let Some(current) = self
+                .fs
+                .get_entity_by_parent_and_block(directory_block, destination.start_block)
+            else {
+                reply.error(EIO);
+                return;
+            };
+
+            let from = if same_file { &current } else { &source };
+
+            if self
+                .fs
+                .read_contents_by_entity(from, &mut buffer, (offset_in + position) as _)
+                .is_err()
+            {
+                reply.error(EIO);
+                return;
+            }
+
+            self.fs.write_contents_by_entity(
+                directory_block,
+                &current,
+                &buffer,
+                (offset_out + position) as _,
+            );
+
+            done += chunk;
+        }
+
+        let Some(copied) = self
+            .fs
+            .get_entity_by_parent_and_block(directory_block, destination.start_block)
+        else {
+            reply.error(EIO);
+            return;
+        };
+
+        self.account(Some(destination.size), Some(copied.size));
+        self.handles.forget_entity(destination.start_block);
+        self.meta
+            .fill_holes(destination.start_block, offset_out, end, block_size);
+        self.meta.touch_modified(destination.start_block, false);
+        self.touch_accessed(&source);
+
+        reply.written(length as u32);
     }
 
     fn getxattr(
@@ -878,18 +3530,54 @@ impl Filesystem for NoctFSFused<'_> {
         reply: fuser::ReplyXattr,
     ) {
         println!(
-            "[Not Implemented] getxattr(ino: {:#x?}, name: {:?}, size: {})",
+            "getxattr(ino: {:#x?}, name: {:?}, size: {})",
             ino, name, size
         );
-        reply.error(ENOSYS);
+
+        let Some(entity) = self.find_entity(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        let name = name.as_bytes();
+
+        if let Err(e) = self.check_xattr_access(_req, &entity, name, false) {
+            reply.error(e);
+            return;
+        }
+
+        match self.meta.xattr(entity.start_block, name) {
+            Some(value) => reply_xattr(reply, size, value),
+            None => reply.error(ENODATA),
+        }
     }
 
     fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
-        println!(
-            "[Not Implemented] listxattr(ino: {:#x?}, size: {})",
-            ino, size
-        );
-        reply.error(ENOSYS);
+        println!("listxattr(ino: {:#x?}, size: {})", ino, size);
+
+        let Some(entity) = self.find_entity(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        let mut names = vec![];
+
+        for name in self
+            .meta
+            .xattrs(entity.start_block)
+            .into_iter()
+            .flatten()
+            .map(|(name, _)| name)
+        {
+            if self.check_xattr_access(_req, &entity, name, false).is_err() {
+                continue;
+            }
+
+            names.extend_from_slice(name);
+            names.push(0);
+        }
+
+        reply_xattr(reply, size, &names);
     }
 
     fn removexattr(
@@ -899,45 +3587,175 @@ impl Filesystem for NoctFSFused<'_> {
         name: &OsStr,
         reply: fuser::ReplyEmpty,
     ) {
-        println!(
-            "[Not Implemented] removexattr(ino: {:#x?}, name: {:?})",
-            ino, name
+        println!("removexattr(ino: {:#x?}, name: {:?})", ino, name);
+
+        let Some(entity) = self.find_entity(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        let name = name.as_bytes();
+
+        if let Err(e) = self.check_xattr_access(_req, &entity, name, true) {
+            reply.error(e);
+            return;
+        }
+
+        if !self.meta.remove_xattr(entity.start_block, name) {
+            reply.error(ENODATA);
+            return;
+        }
+
+        self.meta
+            .touch_changed(entity.start_block, entity.is_directory());
+        self.save_meta();
+
+        reply.ok();
+    }
+}
+
+/// Blocks an entity of `size` bytes takes; even an empty one has a block of its own.
+fn blocks_for(size: u64, block_size: u64) -> u64 {
+    size.div_ceil(block_size).max(1)
+}
+
+/// Primary and supplementary groups of the process behind `req`.
+fn caller_groups(req: &Request<'_>) -> Vec<u32> {
+    let mut groups = vec![req.gid()];
+
+    // FUSE only passes the primary group along, the rest is looked up in procfs.
+    let status = std::fs::read_to_string(format!("/proc/{}/status", req.pid())).unwrap_or_default();
+
+    if let Some(line) = status.lines().find_map(|line| line.strip_prefix("Groups:")) {
+        groups.extend(
+            line.split_whitespace()
+                .filter_map(|group| group.parse::<u32>().ok()),
         );
-        reply.error(ENOSYS);
+    }
+
+    groups
+}
+
+/// Replies with an attribute value or name list, or just its size when `size` is 0.
+fn reply_xattr(reply: fuser::ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if (size as usize) < data.len() {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
     }
 }
 
 fn main() -> io::Result<()> {
-    let filename = std::env::args().skip(1).last().expect("Specify a file!");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let filename = args.last().expect("Specify a file!").clone();
+
+    // Comma separated `-o` options, later ones win.
+    let mount_options: Vec<&str> = args
+        .windows(2)
+        .filter(|pair| pair[0] == "-o")
+        .flat_map(|pair| pair[1].split(','))
+        .collect();
+
+    let atime_policy = mount_options
+        .iter()
+        .fold(AtimePolicy::NoAtime, |policy, option| match *option {
+            "noatime" => AtimePolicy::NoAtime,
+            "relatime" => AtimePolicy::RelAtime,
+            "strictatime" => AtimePolicy::StrictAtime,
+            _ => policy,
+        });
+
+    let sync_writes = mount_options
+        .iter()
+        .fold(true, |sync, option| match *option {
+            "sync" => true,
+            "async" => false,
+            _ => sync,
+        });
+
+    let nodev = mount_options
+        .iter()
+        .fold(true, |nodev, option| match *option {
+            "dev" => false,
+            "nodev" => true,
+            _ => nodev,
+        });
+
+    // Percentage of the image kept for root, as `mke2fs -m` does.
+    let reserve_percent = mount_options
+        .iter()
+        .filter_map(|option| option.strip_prefix("reserve="))
+        .filter_map(|percent| percent.parse::<u64>().ok())
+        .next_back()
+        .unwrap_or(DEFAULT_RESERVE_PERCENT)
+        .min(50);
 
     let file = std::fs::OpenOptions::new()
         .read(true)
         .write(true)
         .open(filename)
         .unwrap();
+    let image_size = file.metadata()?.len();
     let mut device = device::FileDevice(file);
+    let sync_device = device.try_clone()?;
+
+    let mut noct = NoctFS::new(&mut device).unwrap();
+    let root_block = noct.get_root_entity().unwrap().start_block;
+    // Blocks in front of the root directory hold NoctFS's own bookkeeping, not data.
+    let total_blocks = (image_size / noct.block_size() as u64).saturating_sub(root_block);
+
+    // Entities without stored metadata are owned by whoever mounted the image.
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
 
     let fs = NoctFSFused {
-        fhs_opened: vec![],
-        fs: NoctFS::new(&mut device).unwrap(),
-        global_fh: 0,
-        ino_cache: INOCache::new(),
+        fs: noct,
+        device: sync_device,
+        handles: HandleTable::default(),
+        ino_cache: INOCache::new(root_block),
+        meta: MetaStore::new(uid, gid),
+        atime_policy,
+        sync_writes,
+        total_blocks,
+        reserved_blocks: total_blocks * reserve_percent / 100,
+        locks: LockManager::default(),
+        meta_saved: Instant::now(),
+        subdirectories: HashMap::new(),
+        usage: None,
     };
     let mountpoint = String::from("../filesystem");
 
+    let mut options = vec![
+        MountOption::FSName("NoctFS".to_owned()),
+        if nodev {
+            MountOption::NoDev
+        } else {
+            MountOption::Dev
+        },
+        MountOption::NoSuid,
+        if sync_writes {
+            MountOption::Sync
+        } else {
+            MountOption::Async
+        },
+        MountOption::RW,
+        MountOption::DefaultPermissions,
+    ];
+
+    options.push(if atime_policy == AtimePolicy::NoAtime {
+        MountOption::NoAtime
+    } else {
+        MountOption::Atime
+    });
+
+    // Without `user_allow_other` in /etc/fuse.conf only root may let other users in.
+    if unsafe { libc::geteuid() } == 0 {
+        options.push(MountOption::AllowOther);
+    }
+
     std::fs::create_dir(&mountpoint)?;
-    let result = fuser::mount2(
-        fs,
-        &mountpoint,
-        &[
-            MountOption::FSName("NoctFS".to_owned()),
-            MountOption::NoDev,
-            MountOption::NoSuid,
-            MountOption::Sync,
-            MountOption::NoAtime,
-            MountOption::RW,
-        ],
-    );
+    let result = fuser::mount2(fs, &mountpoint, &options);
     std::fs::remove_dir(mountpoint)?;
 
     println!("Result: {:?}", result);
@@ -0,0 +1,786 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, SystemTime},
+};
+
+use noctfs::BlockAddress;
+
+use crate::holes::HoleMap;
+
+/// Name of the hidden file in the root directory that keeps the [`MetaStore`] on the image.
+pub const META_FILE_NAME: &str = ".noctfs-meta";
+
+/// Name a new copy of the store is written under before it takes the place of the old one.
+pub const META_NEW_FILE_NAME: &str = ".noctfs-meta.new";
+
+const MAGIC: &[u8; 4] = b"NFSM";
+
+/// Smallest record: its block and a field count of zero.
+const MIN_RECORD_LENGTH: usize = 10;
+
+const TAG_MODE: u8 = 1;
+const TAG_UID: u8 = 2;
+const TAG_GID: u8 = 3;
+const TAG_ATIME: u8 = 4;
+const TAG_MTIME: u8 = 5;
+const TAG_CTIME: u8 = 6;
+const TAG_CRTIME: u8 = 7;
+const TAG_NLINK: u8 = 8;
+const TAG_LINK_TARGET: u8 = 9;
+const TAG_RDEV: u8 = 10;
+const TAG_XATTR: u8 = 11;
+const TAG_HOLES: u8 = 12;
+const TAG_FLAGS: u8 = 13;
+const TAG_INO: u8 = 14;
+const TAG_GENERATION: u8 = 15;
+
+/// Tags of the fields after the last record, which describe the store as a whole.
+const STORE_TAG_NEXT_INO: u8 = 1;
+const STORE_TAG_FREE_INOS: u8 = 2;
+
+/// Attribute flags `chattr` sets, with the values of `linux/fs.h`.
+pub const FS_IMMUTABLE_FL: u32 = 0x10;
+pub const FS_APPEND_FL: u32 = 0x20;
+pub const FS_NODUMP_FL: u32 = 0x40;
+
+/// When reads should update the access time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AtimePolicy {
+    /// Never.
+    NoAtime,
+    /// Only if the access time is older than the last change, or than a day.
+    RelAtime,
+    /// On every access.
+    StrictAtime,
+}
+
+/// Attributes NoctFS has no room for in its entity headers.
+#[derive(Clone, Debug)]
+pub struct EntityMeta {
+    /// File type and permission bits, as in `st_mode`.
+    ///
+    /// The file type is only stored for kinds NoctFS can't express itself (symlinks, FIFOs,
+    /// sockets and device nodes);
+    /// when it's zero the entity is whatever its header says, a directory or a regular file.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    /// Birth time.
+    pub crtime: SystemTime,
+    /// Number of names the file has. Unused for directories, their count is derived.
+    pub nlink: u32,
+    /// Set on the extra names of a hard-linked file: the `start_block` of the entity
+    /// that actually holds the data and metadata.
+    pub link_target: Option<BlockAddress>,
+    /// Device number of character and block device nodes.
+    pub rdev: u32,
+    /// `FS_*_FL` attribute flags.
+    pub flags: u32,
+    /// Inode number the entity was given, or 0 if it has none yet.
+    pub ino: u64,
+    /// Generation of `ino`.
+    pub generation: u64,
+}
+
+/// Extended attributes of one entity, by name.
+pub type Xattrs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Most extended attributes one entity can have. Each is a field of the entity's record,
+/// whose field count has to fit in a `u16` along with everything else.
+pub const MAX_XATTR_COUNT: usize = 1024;
+
+/// Most bytes of names and values all extended attributes of one entity can take together.
+pub const MAX_XATTRS_LENGTH: usize = 256 * 1024;
+
+/// Per-entity metadata keyed by the entity's `start_block`.
+///
+/// Serialized as a list of records made of tagged fields, so newer fields can be added
+/// without breaking images written by older versions: unknown tags are skipped on load.
+pub struct MetaStore {
+    entries: HashMap<BlockAddress, EntityMeta>,
+    /// Kept apart from [`EntityMeta`] so copying the latter around stays cheap.
+    xattrs: HashMap<BlockAddress, Xattrs>,
+    holes: HashMap<BlockAddress, HoleMap>,
+    /// Inode numbering, see [`crate::ino_cache::INOCache::restore`].
+    next_ino: u64,
+    free_inos: Vec<(u64, u64)>,
+    default_uid: u32,
+    default_gid: u32,
+    dirty: bool,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        let chunk = self.data.get(self.position..self.position + count)?;
+        self.position += count;
+
+        Some(chunk)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
+
+/// Encodes a timestamp as signed seconds and nanoseconds relative to the Unix epoch.
+fn time_to_bytes(time: SystemTime) -> [u8; 12] {
+    let (seconds, nanoseconds) = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(after) => (after.as_secs() as i64, after.subsec_nanos()),
+        Err(e) => {
+            let before = e.duration();
+
+            if before.subsec_nanos() == 0 {
+                (-(before.as_secs() as i64), 0)
+            } else {
+                (
+                    -(before.as_secs() as i64) - 1,
+                    1_000_000_000 - before.subsec_nanos(),
+                )
+            }
+        }
+    };
+
+    let mut out = [0u8; 12];
+    out[..8].copy_from_slice(&seconds.to_le_bytes());
+    out[8..].copy_from_slice(&nanoseconds.to_le_bytes());
+
+    out
+}
+
+fn time_from_bytes(value: &[u8]) -> Option<SystemTime> {
+    let seconds = i64::from_le_bytes(value.get(..8)?.try_into().ok()?);
+    let nanoseconds = u32::from_le_bytes(value.get(8..12)?.try_into().ok()?);
+
+    let base = if seconds >= 0 {
+        SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(seconds as u64))?
+    } else {
+        SystemTime::UNIX_EPOCH.checked_sub(Duration::from_secs(seconds.unsigned_abs()))?
+    };
+
+    base.checked_add(Duration::from_nanos(nanoseconds as u64))
+}
+
+fn put_field(out: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    out.push(tag);
+    out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+impl MetaStore {
+    pub fn new(default_uid: u32, default_gid: u32) -> Self {
+        Self {
+            entries: HashMap::new(),
+            xattrs: HashMap::new(),
+            holes: HashMap::new(),
+            next_ino: 0,
+            free_inos: vec![],
+            default_uid,
+            default_gid,
+            dirty: false,
+        }
+    }
+
+    fn default_meta(&self, is_directory: bool) -> EntityMeta {
+        EntityMeta {
+            mode: if is_directory { 0o755 } else { 0o644 },
+            uid: self.default_uid,
+            gid: self.default_gid,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            nlink: 1,
+            link_target: None,
+            rdev: 0,
+            flags: 0,
+            ino: 0,
+            generation: 0,
+        }
+    }
+
+    /// Returns the metadata of the entity at `block`, or defaults if it has none stored.
+    pub fn get(&self, block: BlockAddress, is_directory: bool) -> EntityMeta {
+        self.entries
+            .get(&block)
+            .cloned()
+            .unwrap_or_else(|| self.default_meta(is_directory))
+    }
+
+    /// Returns the stored metadata for changing, creating it from defaults if needed.
+    pub fn get_mut(&mut self, block: BlockAddress, is_directory: bool) -> &mut EntityMeta {
+        let default = self.default_meta(is_directory);
+
+        self.dirty = true;
+        self.entries.entry(block).or_insert(default)
+    }
+
+    pub fn insert(&mut self, block: BlockAddress, meta: EntityMeta) {
+        self.dirty = true;
+        self.entries.insert(block, meta);
+    }
+
+    /// Marks the contents of the entity at `block` as modified just now.
+    pub fn touch_modified(&mut self, block: BlockAddress, is_directory: bool) {
+        let now = SystemTime::now();
+        let meta = self.get_mut(block, is_directory);
+
+        meta.mtime = now;
+        meta.ctime = now;
+    }
+
+    /// Marks the attributes of the entity at `block` as changed just now.
+    pub fn touch_changed(&mut self, block: BlockAddress, is_directory: bool) {
+        self.get_mut(block, is_directory).ctime = SystemTime::now();
+    }
+
+    pub fn remove(&mut self, block: BlockAddress) {
+        if self.entries.remove(&block).is_some() {
+            self.dirty = true;
+        }
+
+        self.xattrs.remove(&block);
+        self.holes.remove(&block);
+    }
+
+    pub fn xattrs(&self, block: BlockAddress) -> Option<&Xattrs> {
+        self.xattrs.get(&block)
+    }
+
+    pub fn xattr(&self, block: BlockAddress, name: &[u8]) -> Option<&[u8]> {
+        self.xattrs.get(&block)?.get(name).map(Vec::as_slice)
+    }
+
+    pub fn set_xattr(
+        &mut self,
+        block: BlockAddress,
+        is_directory: bool,
+        name: &[u8],
+        value: &[u8],
+    ) {
+        // Attributes are written out as part of the entity's record, so it has to have one.
+        self.get_mut(block, is_directory);
+
+        self.xattrs
+            .entry(block)
+            .or_default()
+            .insert(name.to_vec(), value.to_vec());
+    }
+
+    /// Whether setting `name` to a value of `value_length` bytes keeps the entity at `block`
+    /// within [`MAX_XATTR_COUNT`] and [`MAX_XATTRS_LENGTH`].
+    pub fn xattr_fits(&self, block: BlockAddress, name: &[u8], value_length: usize) -> bool {
+        let (mut count, mut length) = (1, name.len() + value_length);
+
+        for (other, value) in self.xattrs.get(&block).into_iter().flatten() {
+            if other != name {
+                count += 1;
+                length += other.len() + value.len();
+            }
+        }
+
+        count <= MAX_XATTR_COUNT && length <= MAX_XATTRS_LENGTH
+    }
+
+    /// Returns `false` if there was no such attribute.
+    pub fn remove_xattr(&mut self, block: BlockAddress, name: &[u8]) -> bool {
+        let Some(xattrs) = self.xattrs.get_mut(&block) else {
+            return false;
+        };
+
+        if xattrs.remove(name).is_none() {
+            return false;
+        }
+
+        if xattrs.is_empty() {
+            self.xattrs.remove(&block);
+        }
+
+        self.dirty = true;
+
+        true
+    }
+
+    /// Follows an entity whose data moved to a new starting block, along with its hard links.
+    pub fn rekey(&mut self, old_block: BlockAddress, new_block: BlockAddress) {
+        if let Some(meta) = self.entries.remove(&old_block) {
+            self.dirty = true;
+            self.entries.insert(new_block, meta);
+        }
+
+        if let Some(xattrs) = self.xattrs.remove(&old_block) {
+            self.dirty = true;
+            self.xattrs.insert(new_block, xattrs);
+        }
+
+        if let Some(holes) = self.holes.remove(&old_block) {
+            self.dirty = true;
+            self.holes.insert(new_block, holes);
+        }
+
+        for meta in self.entries.values_mut() {
+            if meta.link_target == Some(old_block) {
+                meta.link_target = Some(new_block);
+                self.dirty = true;
+            }
+        }
+    }
+
+    pub fn holes(&self, block: BlockAddress) -> Option<&HoleMap> {
+        self.holes.get(&block)
+    }
+
+    /// Marks `start..end` of the file at `block`, `size` bytes long, as never written.
+    pub fn punch_hole(
+        &mut self,
+        block: BlockAddress,
+        start: u64,
+        end: u64,
+        block_size: u64,
+        size: u64,
+    ) {
+        let mut holes = self.holes.remove(&block).unwrap_or_default();
+        holes.punch(start, end, block_size, size);
+
+        if !holes.is_empty() {
+            // Holes are written out as part of the entity's record, so it has to have one.
+            self.get_mut(block, false);
+            self.holes.insert(block, holes);
+        }
+    }
+
+    /// Marks `start..end` of the file at `block` as holding data.
+    pub fn fill_holes(&mut self, block: BlockAddress, start: u64, end: u64, block_size: u64) {
+        let Some(holes) = self.holes.get_mut(&block) else {
+            return;
+        };
+
+        if !holes.fill(start, end, block_size) {
+            return;
+        }
+
+        if holes.is_empty() {
+            self.holes.remove(&block);
+        }
+
+        self.dirty = true;
+    }
+
+    /// Forgets the holes past `size` after the file at `block` was cut short.
+    pub fn truncate_holes(&mut self, block: BlockAddress, size: u64) {
+        let Some(holes) = self.holes.get_mut(&block) else {
+            return;
+        };
+
+        holes.truncate(size);
+
+        if holes.is_empty() {
+            self.holes.remove(&block);
+        }
+
+        self.dirty = true;
+    }
+
+    /// Inode numbering as of the last save or load.
+    pub fn numbering(&self) -> (u64, &[(u64, u64)]) {
+        (self.next_ino, &self.free_inos)
+    }
+
+    /// Sets the inode numbering to save along with the records.
+    pub fn set_numbering(&mut self, next_ino: u64, free_inos: &[(u64, u64)]) {
+        self.next_ino = next_ino;
+        self.free_inos = free_inos.to_vec();
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + self.entries.len() * 112);
+
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+
+        for (block, meta) in &self.entries {
+            out.extend_from_slice(&block.to_le_bytes());
+
+            let fields_position = out.len();
+            out.extend_from_slice(&0u16.to_le_bytes());
+
+            let mut fields = 0u16;
+            let mut field = |out: &mut Vec<u8>, tag: u8, value: &[u8]| {
+                put_field(out, tag, value);
+                fields += 1;
+            };
+
+            field(&mut out, TAG_MODE, &meta.mode.to_le_bytes());
+            field(&mut out, TAG_UID, &meta.uid.to_le_bytes());
+            field(&mut out, TAG_GID, &meta.gid.to_le_bytes());
+            field(&mut out, TAG_ATIME, &time_to_bytes(meta.atime));
+            field(&mut out, TAG_MTIME, &time_to_bytes(meta.mtime));
+            field(&mut out, TAG_CTIME, &time_to_bytes(meta.ctime));
+            field(&mut out, TAG_CRTIME, &time_to_bytes(meta.crtime));
+            field(&mut out, TAG_NLINK, &meta.nlink.to_le_bytes());
+
+            if let Some(target) = meta.link_target {
+                field(&mut out, TAG_LINK_TARGET, &target.to_le_bytes());
+            }
+
+            if meta.rdev != 0 {
+                field(&mut out, TAG_RDEV, &meta.rdev.to_le_bytes());
+            }
+
+            if meta.flags != 0 {
+                field(&mut out, TAG_FLAGS, &meta.flags.to_le_bytes());
+            }
+
+            if meta.ino != 0 {
+                field(&mut out, TAG_INO, &meta.ino.to_le_bytes());
+                field(&mut out, TAG_GENERATION, &meta.generation.to_le_bytes());
+            }
+
+            // One field per attribute: name length, name, value. `setxattr` keeps the count
+            // below the limit, the cut only guards the field counter.
+            for (name, value) in self
+                .xattrs
+                .get(block)
+                .into_iter()
+                .flatten()
+                .take(MAX_XATTR_COUNT)
+            {
+                let mut encoded = Vec::with_capacity(2 + name.len() + value.len());
+                encoded.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                encoded.extend_from_slice(name);
+                encoded.extend_from_slice(value);
+
+                field(&mut out, TAG_XATTR, &encoded);
+            }
+
+            if let Some(holes) = self.holes.get(block) {
+                let encoded: Vec<u8> = holes
+                    .ranges()
+                    .iter()
+                    .flat_map(|(start, end)| [start.to_le_bytes(), end.to_le_bytes()])
+                    .flatten()
+                    .collect();
+
+                field(&mut out, TAG_HOLES, &encoded);
+            }
+
+            out[fields_position..fields_position + 2].copy_from_slice(&fields.to_le_bytes());
+        }
+
+        // Stores written before inode numbers were kept end with the last record, and
+        // loading one stops there, so the numbering goes after it.
+        let free_inos: Vec<u8> = self
+            .free_inos
+            .iter()
+            .flat_map(|(ino, generation)| [ino.to_le_bytes(), generation.to_le_bytes()])
+            .flatten()
+            .collect();
+
+        out.extend_from_slice(&2u16.to_le_bytes());
+        put_field(&mut out, STORE_TAG_NEXT_INO, &self.next_ino.to_le_bytes());
+        put_field(&mut out, STORE_TAG_FREE_INOS, &free_inos);
+
+        out
+    }
+
+    /// Replaces the contents of the store with records parsed from `data`.
+    pub fn load(&mut self, data: &[u8]) -> Option<()> {
+        let mut reader = Reader { data, position: 0 };
+
+        if reader.take(4)? != MAGIC {
+            return None;
+        }
+
+        let count = reader.u32()?;
+        // The count comes from the image, so it's only trusted as far as the data can back it.
+        let mut entries =
+            HashMap::with_capacity((count as usize).min(data.len() / MIN_RECORD_LENGTH));
+        let mut xattrs: HashMap<BlockAddress, Xattrs> = HashMap::new();
+        let mut holes = HashMap::new();
+
+        for _ in 0..count {
+            let block = reader.u64()?;
+            let fields = reader.u16()?;
+
+            let mut meta = self.default_meta(false);
+
+            for _ in 0..fields {
+                let tag = reader.u8()?;
+                let length = reader.u32()? as usize;
+                let value = reader.take(length)?;
+
+                match tag {
+                    TAG_MODE => meta.mode = u32::from_le_bytes(value.try_into().ok()?),
+                    TAG_UID => meta.uid = u32::from_le_bytes(value.try_into().ok()?),
+                    TAG_GID => meta.gid = u32::from_le_bytes(value.try_into().ok()?),
+                    TAG_ATIME => meta.atime = time_from_bytes(value)?,
+                    TAG_MTIME => meta.mtime = time_from_bytes(value)?,
+                    TAG_CTIME => meta.ctime = time_from_bytes(value)?,
+                    TAG_CRTIME => meta.crtime = time_from_bytes(value)?,
+                    TAG_NLINK => meta.nlink = u32::from_le_bytes(value.try_into().ok()?),
+                    TAG_LINK_TARGET => {
+                        meta.link_target = Some(u64::from_le_bytes(value.try_into().ok()?))
+                    }
+                    TAG_RDEV => meta.rdev = u32::from_le_bytes(value.try_into().ok()?),
+                    TAG_FLAGS => meta.flags = u32::from_le_bytes(value.try_into().ok()?),
+                    TAG_INO => meta.ino = u64::from_le_bytes(value.try_into().ok()?),
+                    TAG_GENERATION => meta.generation = u64::from_le_bytes(value.try_into().ok()?),
+                    TAG_XATTR => {
+                        let mut attribute = Reader {
+                            data: value,
+                            position: 0,
+                        };
+
+                        let name_length = attribute.u16()? as usize;
+                        let name = attribute.take(name_length)?.to_vec();
+                        let value = value[attribute.position..].to_vec();
+
+                        xattrs.entry(block).or_default().insert(name, value);
+                    }
+                    TAG_HOLES => {
+                        let ranges = value
+                            .chunks_exact(16)
+                            .map(|range| {
+                                (
+                                    u64::from_le_bytes(range[..8].try_into().unwrap()),
+                                    u64::from_le_bytes(range[8..].try_into().unwrap()),
+                                )
+                            })
+                            .collect();
+
+                        holes.insert(block, HoleMap::from_ranges(ranges)?);
+                    }
+                    _ => {}
+                }
+            }
+
+            entries.insert(block, meta);
+        }
+
+        // Numbers past the highest one in use are free even if the numbering wasn't saved.
+        let mut next_ino = entries.values().map(|meta| meta.ino + 1).max().unwrap_or(0);
+        let mut free_inos = vec![];
+
+        if reader.position < data.len() {
+            let fields = reader.u16()?;
+
+            for _ in 0..fields {
+                let tag = reader.u8()?;
+                let length = reader.u32()? as usize;
+                let value = reader.take(length)?;
+
+                match tag {
+                    STORE_TAG_NEXT_INO => {
+                        next_ino = next_ino.max(u64::from_le_bytes(value.try_into().ok()?))
+                    }
+                    STORE_TAG_FREE_INOS => {
+                        free_inos = value
+                            .chunks_exact(16)
+                            .map(|free| {
+                                (
+                                    u64::from_le_bytes(free[..8].try_into().unwrap()),
+                                    u64::from_le_bytes(free[8..].try_into().unwrap()),
+                                )
+                            })
+                            .collect();
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        self.entries = entries;
+        self.xattrs = xattrs;
+        self.holes = holes;
+        self.next_ino = next_ino;
+        self.free_inos = free_inos;
+        self.dirty = false;
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> EntityMeta {
+        EntityMeta {
+            mode: 0o100640,
+            uid: 1000,
+            gid: 100,
+            atime: SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 5),
+            mtime: SystemTime::UNIX_EPOCH - Duration::new(1, 500_000_000),
+            ctime: SystemTime::UNIX_EPOCH + Duration::from_secs(7),
+            crtime: SystemTime::UNIX_EPOCH,
+            nlink: 3,
+            link_target: Some(77),
+            rdev: 0x0801,
+            flags: FS_APPEND_FL,
+            ino: 42,
+            generation: 6,
+        }
+    }
+
+    fn assert_same(loaded: &EntityMeta, expected: &EntityMeta) {
+        assert_eq!(loaded.mode, expected.mode);
+        assert_eq!((loaded.uid, loaded.gid), (expected.uid, expected.gid));
+        assert_eq!(loaded.atime, expected.atime);
+        assert_eq!(loaded.mtime, expected.mtime);
+        assert_eq!(loaded.ctime, expected.ctime);
+        assert_eq!(loaded.crtime, expected.crtime);
+        assert_eq!(loaded.nlink, expected.nlink);
+        assert_eq!(loaded.link_target, expected.link_target);
+        assert_eq!(loaded.rdev, expected.rdev);
+        assert_eq!(loaded.flags, expected.flags);
+        assert_eq!(
+            (loaded.ino, loaded.generation),
+            (expected.ino, expected.generation)
+        );
+    }
+
+    #[test]
+    fn records_survive_a_round_trip() {
+        let mut store = MetaStore::new(0, 0);
+        let meta = sample();
+
+        store.insert(10, meta.clone());
+        store.set_xattr(10, false, b"user.color", b"blue");
+        store.set_xattr(20, true, b"user.empty", b"");
+        store.punch_hole(10, 4096, 3 * 4096, 4096, 4 * 4096);
+        store.set_numbering(50, &[(4, 2), (9, 1)]);
+
+        let mut loaded = MetaStore::new(1, 1);
+        loaded.load(&store.to_bytes()).unwrap();
+
+        assert_same(&loaded.get(10, false), &meta);
+        assert_eq!(loaded.xattr(10, b"user.color"), Some(&b"blue"[..]));
+        assert_eq!(loaded.xattr(20, b"user.empty"), Some(&b""[..]));
+        assert_eq!(loaded.holes(10).unwrap().ranges(), &[(4096, 3 * 4096)]);
+        assert_eq!(loaded.numbering(), (50, &[(4, 2), (9, 1)][..]));
+        assert!(!loaded.is_dirty());
+
+        // Stored with defaults, since it has an attribute.
+        assert_eq!(loaded.get(20, true).mode, 0o755);
+        assert_eq!(loaded.get(20, true).uid, 0);
+    }
+
+    #[test]
+    fn entities_without_a_record_get_defaults() {
+        let store = MetaStore::new(1000, 100);
+        let meta = store.get(10, true);
+
+        assert_eq!(meta.mode, 0o755);
+        assert_eq!((meta.uid, meta.gid), (1000, 100));
+        assert_eq!(store.get(10, false).mode, 0o644);
+    }
+
+    #[test]
+    fn stores_written_before_the_numbering_still_load() {
+        let mut store = MetaStore::new(0, 0);
+        let meta = sample();
+        store.insert(10, meta.clone());
+
+        // Without freed numbers the trailer is a field count, the next inode and an empty list.
+        let data = store.to_bytes();
+        let trailer = 2 + (1 + 4 + 8) + (1 + 4);
+
+        let mut loaded = MetaStore::new(0, 0);
+        loaded.load(&data[..data.len() - trailer]).unwrap();
+
+        assert_same(&loaded.get(10, false), &meta);
+        assert_eq!(loaded.numbering(), (meta.ino + 1, &[][..]));
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&10u64.to_le_bytes());
+        data.extend_from_slice(&2u16.to_le_bytes());
+        put_field(&mut data, 200, b"from the future");
+        put_field(&mut data, TAG_UID, &1234u32.to_le_bytes());
+
+        let mut store = MetaStore::new(0, 0);
+        store.load(&data).unwrap();
+
+        assert_eq!(store.get(10, false).uid, 1234);
+    }
+
+    #[test]
+    fn damaged_stores_are_refused() {
+        let mut store = MetaStore::new(0, 0);
+        store.insert(10, sample());
+
+        let data = store.to_bytes();
+
+        assert!(MetaStore::new(0, 0).load(b"XXXX").is_none());
+        assert!(MetaStore::new(0, 0).load(&data[..data.len() / 2]).is_none());
+
+        // A record count far past what the data holds.
+        let mut huge = MAGIC.to_vec();
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(MetaStore::new(0, 0).load(&huge).is_none());
+    }
+
+    #[test]
+    fn rekeying_moves_everything_and_follows_hard_links() {
+        let mut store = MetaStore::new(0, 0);
+        let meta = sample();
+        let mut link = sample();
+        link.link_target = Some(10);
+
+        store.insert(10, meta.clone());
+        store.insert(30, link);
+        store.set_xattr(10, false, b"user.color", b"blue");
+        store.punch_hole(10, 0, 4096, 4096, 2 * 4096);
+
+        store.rekey(10, 20);
+
+        assert_same(&store.get(20, false), &meta);
+        assert_eq!(store.get(10, false).mode, 0o644);
+        assert_eq!(store.xattr(20, b"user.color"), Some(&b"blue"[..]));
+        assert_eq!(store.xattr(10, b"user.color"), None);
+        assert!(store.holes(10).is_none() && store.holes(20).is_some());
+        assert_eq!(store.get(30, false).link_target, Some(20));
+    }
+
+    #[test]
+    fn xattr_limits_count_what_the_new_value_replaces() {
+        let mut store = MetaStore::new(0, 0);
+
+        store.set_xattr(10, false, b"user.a", &vec![0; MAX_XATTRS_LENGTH - 6]);
+
+        assert!(!store.xattr_fits(10, b"user.b", 0));
+        assert!(store.xattr_fits(10, b"user.a", MAX_XATTRS_LENGTH - 6));
+        assert!(!store.xattr_fits(10, b"user.a", MAX_XATTRS_LENGTH - 5));
+        assert!(store.xattr_fits(20, b"user.b", 0));
+    }
+}
@@ -0,0 +1,60 @@
+//! Helpers for the tests that run against a mounted NoctFS image.
+//!
+//! They need a live mount, so they're ignored by default. Mount a scratch image, point
+//! `NOCTFS_TEST_MOUNT` at its mountpoint and run them with `cargo test -- --ignored`.
+//! Tests that check what survives a remount also need `NOCTFS_TEST_REMOUNT`, a shell
+//! command that unmounts the image and mounts it again at the same place.
+#![allow(dead_code)]
+
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{self, Command},
+};
+
+/// A fresh directory for one test, removed again when dropped.
+pub struct Scratch {
+    pub path: PathBuf,
+}
+
+impl Scratch {
+    /// Creates the directory `name` on the mounted image.
+    pub fn on_image(name: &str) -> Self {
+        let mount = env::var_os("NOCTFS_TEST_MOUNT")
+            .expect("NOCTFS_TEST_MOUNT has to point at the mountpoint of a scratch image");
+
+        Self::under(PathBuf::from(mount), name)
+    }
+
+    /// Creates the directory `name` on the file system NoctFS is compared against:
+    /// `NOCTFS_TEST_REFERENCE`, or the tmpfs at `/dev/shm`.
+    pub fn on_reference(name: &str) -> Self {
+        let base = env::var_os("NOCTFS_TEST_REFERENCE").unwrap_or_else(|| "/dev/shm".into());
+
+        Self::under(PathBuf::from(base), name)
+    }
+
+    fn under(base: PathBuf, name: &str) -> Self {
+        let path = base.join(format!("noctfs-{name}-{}", process::id()));
+
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir(&path).unwrap();
+
+        Self { path }
+    }
+}
+
+/// Unmounts the image and mounts it again through `NOCTFS_TEST_REMOUNT`.
+pub fn remount() {
+    let command = env::var("NOCTFS_TEST_REMOUNT")
+        .expect("NOCTFS_TEST_REMOUNT has to hold a command that remounts the scratch image");
+
+    let status = Command::new("sh").arg("-c").arg(&command).status().unwrap();
+    assert!(status.success(), "remounting with {command:?} failed");
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
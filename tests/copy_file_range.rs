@@ -0,0 +1,107 @@
+mod common;
+
+use std::{
+    fs::{self, File},
+    os::fd::AsRawFd,
+    path::Path,
+};
+
+use common::Scratch;
+
+/// Bytes that differ from block to block, so data copied to the wrong place shows up.
+fn pattern(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i % 251) as u8).collect()
+}
+
+/// Copies `length` bytes from `offset_in` of `from` to `offset_out` of `to` through
+/// `copy_file_range`, which may take several calls.
+fn copy_range(from: &File, mut offset_in: i64, to: &File, mut offset_out: i64, length: usize) {
+    let mut left = length;
+
+    while left > 0 {
+        let copied = unsafe {
+            libc::copy_file_range(
+                from.as_raw_fd(),
+                &mut offset_in,
+                to.as_raw_fd(),
+                &mut offset_out,
+                left,
+                0,
+            )
+        };
+
+        assert!(
+            copied > 0,
+            "copy_file_range: {}",
+            std::io::Error::last_os_error()
+        );
+        left -= copied as usize;
+    }
+}
+
+/// Runs `steps` in a scratch directory on the image and on the reference, and checks that
+/// the file `destination` ends up the same in both.
+fn compare(name: &str, steps: impl Fn(&Path)) {
+    let image = Scratch::on_image(name);
+    let reference = Scratch::on_reference(name);
+
+    for scratch in [&image, &reference] {
+        steps(&scratch.path);
+    }
+
+    let expected = fs::read(reference.path.join("destination")).unwrap();
+    let actual = fs::read(image.path.join("destination")).unwrap();
+
+    assert_eq!(actual.len(), expected.len());
+    assert!(actual == expected, "contents differ from the reference");
+}
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn copying_between_files_matches_the_reference() {
+    compare("copy-between", |directory| {
+        fs::write(directory.join("source"), pattern(100_000)).unwrap();
+        fs::write(directory.join("destination"), b"existing").unwrap();
+
+        let from = File::open(directory.join("source")).unwrap();
+        let to = File::options()
+            .write(true)
+            .open(directory.join("destination"))
+            .unwrap();
+
+        copy_range(&from, 1_000, &to, 4, 50_000);
+    });
+}
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn copying_past_the_end_leaves_zeros_before_it() {
+    compare("copy-past-end", |directory| {
+        fs::write(directory.join("source"), pattern(30_000)).unwrap();
+        fs::write(directory.join("destination"), pattern(100)).unwrap();
+
+        let from = File::open(directory.join("source")).unwrap();
+        let to = File::options()
+            .write(true)
+            .open(directory.join("destination"))
+            .unwrap();
+
+        copy_range(&from, 0, &to, 70_000, 30_000);
+    });
+}
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn copying_within_one_file_matches_the_reference() {
+    compare("copy-within", |directory| {
+        fs::write(directory.join("destination"), pattern(60_000)).unwrap();
+
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(directory.join("destination"))
+            .unwrap();
+
+        copy_range(&file, 0, &file, 40_000, 20_000);
+    });
+}
@@ -0,0 +1,102 @@
+mod common;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::ErrorKind,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use common::Scratch;
+
+/// Builds a tree `depth` directories deep with a file and a sibling directory on every level.
+fn build_tree(root: &Path, depth: usize) -> Vec<PathBuf> {
+    let mut nodes = vec![];
+    let mut directory = root.to_path_buf();
+
+    for level in 0..depth {
+        let file = directory.join(format!("file-{level}"));
+        fs::write(&file, format!("level {level}")).unwrap();
+
+        let sibling = directory.join(format!("sibling-{level}"));
+        fs::create_dir(&sibling).unwrap();
+
+        directory = directory.join(format!("dir-{level}"));
+        fs::create_dir(&directory).unwrap();
+
+        nodes.extend([file, sibling, directory.clone()]);
+    }
+
+    nodes
+}
+
+fn inodes(nodes: &[PathBuf]) -> HashMap<PathBuf, u64> {
+    nodes
+        .iter()
+        .map(|node| (node.clone(), fs::symlink_metadata(node).unwrap().ino()))
+        .collect()
+}
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn every_node_of_a_nested_tree_resolves_to_its_own_inode() {
+    let scratch = Scratch::on_image("inodes-nested");
+
+    let nodes = build_tree(&scratch.path, 8);
+    let first = inodes(&nodes);
+
+    let unique: HashSet<u64> = first.values().copied().collect();
+    assert_eq!(unique.len(), nodes.len());
+
+    // Deepest first, so nothing is resolved through a parent that was just looked up.
+    for node in nodes.iter().rev() {
+        assert_eq!(fs::symlink_metadata(node).unwrap().ino(), first[node]);
+    }
+
+    for level in 0..8 {
+        let file = nodes[level * 3].clone();
+        assert_eq!(fs::read_to_string(file).unwrap(), format!("level {level}"));
+    }
+}
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn inodes_stay_the_same_when_a_tree_moves() {
+    let scratch = Scratch::on_image("inodes-move");
+
+    let source = scratch.path.join("source");
+    fs::create_dir(&source).unwrap();
+
+    let nodes = build_tree(&source, 4);
+    let before = inodes(&nodes);
+
+    let destination = scratch.path.join("destination");
+    fs::create_dir(&destination).unwrap();
+
+    let moved = destination.join("moved");
+    fs::rename(&source, &moved).unwrap();
+
+    for node in &nodes {
+        let relocated = moved.join(node.strip_prefix(&source).unwrap());
+        assert_eq!(fs::symlink_metadata(relocated).unwrap().ino(), before[node]);
+    }
+}
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn removed_nodes_stop_resolving() {
+    let scratch = Scratch::on_image("inodes-removed");
+
+    let nodes = build_tree(&scratch.path, 3);
+    let file = &nodes[3];
+
+    fs::remove_file(file).unwrap();
+
+    let error = fs::symlink_metadata(file).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::NotFound);
+
+    for node in nodes.iter().filter(|node| *node != file) {
+        assert!(fs::symlink_metadata(node).is_ok());
+    }
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::fs::MetadataExt,
+};
+
+use common::{Scratch, remount};
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn an_open_file_outlives_its_name_with_the_same_inode() {
+    let scratch = Scratch::on_image("orphans-open");
+
+    let path = scratch.path.join("file");
+    let contents = vec![7u8; 200_000];
+    fs::write(&path, &contents).unwrap();
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    let ino = file.metadata().unwrap().ino();
+
+    fs::remove_file(&path).unwrap();
+    assert!(!path.exists());
+
+    let metadata = file.metadata().unwrap();
+    assert_eq!(metadata.ino(), ino);
+    assert_eq!(metadata.nlink(), 0);
+
+    file.seek(SeekFrom::End(0)).unwrap();
+    file.write_all(b"more").unwrap();
+
+    let mut read = vec![];
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut read).unwrap();
+
+    assert_eq!(&read[..contents.len()], &contents[..]);
+    assert_eq!(&read[contents.len()..], b"more");
+}
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn a_hard_link_keeps_the_data_after_the_first_name_goes() {
+    let scratch = Scratch::on_image("orphans-link");
+
+    let first = scratch.path.join("first");
+    let second = scratch.path.join("second");
+    fs::write(&first, b"shared").unwrap();
+    fs::hard_link(&first, &second).unwrap();
+
+    let ino = fs::metadata(&first).unwrap().ino();
+    fs::remove_file(&first).unwrap();
+
+    let metadata = fs::metadata(&second).unwrap();
+    assert_eq!(metadata.ino(), ino);
+    assert_eq!(metadata.nlink(), 1);
+    assert_eq!(fs::read(&second).unwrap(), b"shared");
+
+    // The data now lives in the orphans directory, which has to be found again.
+    remount();
+
+    let names: Vec<_> = fs::read_dir(&scratch.path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(names, ["second"]);
+
+    let metadata = fs::metadata(&second).unwrap();
+    assert_eq!(metadata.ino(), ino);
+    assert_eq!(metadata.nlink(), 1);
+    assert_eq!(fs::read(&second).unwrap(), b"shared");
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use std::{
+    collections::HashSet,
+    ffi::{CStr, CString},
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use common::Scratch;
+
+/// Reads the names left in the open directory stream `stream`.
+fn read_names(stream: *mut libc::DIR) -> HashSet<String> {
+    let mut names = HashSet::new();
+
+    loop {
+        let entry = unsafe { libc::readdir(stream) };
+
+        if entry.is_null() {
+            return names;
+        }
+
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        names.insert(name.to_string_lossy().into_owned());
+    }
+}
+
+fn open_stream(path: &Path) -> *mut libc::DIR {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let stream = unsafe { libc::opendir(path.as_ptr()) };
+    assert!(!stream.is_null());
+
+    stream
+}
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn rewinding_sees_entries_added_since_opening() {
+    let scratch = Scratch::on_image("readdir-rewind");
+
+    fs::write(scratch.path.join("before"), b"").unwrap();
+
+    let stream = open_stream(&scratch.path);
+    let first = read_names(stream);
+
+    fs::write(scratch.path.join("after"), b"").unwrap();
+    fs::remove_file(scratch.path.join("before")).unwrap();
+
+    unsafe { libc::rewinddir(stream) };
+    let second = read_names(stream);
+
+    unsafe { libc::closedir(stream) };
+
+    assert!(first.contains("before") && !first.contains("after"));
+    assert!(second.contains("after") && !second.contains("before"));
+    assert!(second.contains(".") && second.contains(".."));
+}
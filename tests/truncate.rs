@@ -0,0 +1,145 @@
+mod common;
+
+use std::{
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use common::Scratch;
+
+/// Bytes that differ from block to block, so a tail that comes back shows up.
+fn pattern(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i % 251) as u8).collect()
+}
+
+/// Free bytes on the file system holding `path`.
+fn free_bytes(path: &Path) -> u64 {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let mut stats = MaybeUninit::<libc::statvfs>::uninit();
+
+    assert_eq!(
+        unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) },
+        0
+    );
+    let stats = unsafe { stats.assume_init() };
+
+    stats.f_bfree * stats.f_frsize
+}
+
+/// Runs `steps` on a file in a scratch directory on the image and on the reference, and
+/// checks that both end up with the same size and contents.
+fn compare(name: &str, steps: impl Fn(&Path)) {
+    let image = Scratch::on_image(name);
+    let reference = Scratch::on_reference(name);
+
+    for scratch in [&image, &reference] {
+        steps(&scratch.path.join("file"));
+    }
+
+    let expected = fs::read(reference.path.join("file")).unwrap();
+    let actual = fs::read(image.path.join("file")).unwrap();
+
+    assert_eq!(
+        fs::metadata(image.path.join("file")).unwrap().len(),
+        expected.len() as u64
+    );
+    assert_eq!(actual.len(), expected.len());
+    assert!(actual == expected, "contents differ from the reference");
+}
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn shrinking_keeps_the_head() {
+    compare("truncate-shrink", |file| {
+        fs::write(file, pattern(100_000)).unwrap();
+        File::options()
+            .write(true)
+            .open(file)
+            .unwrap()
+            .set_len(5_000)
+            .unwrap();
+    });
+}
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn growing_reads_back_zeros() {
+    compare("truncate-grow", |file| {
+        fs::write(file, pattern(3_000)).unwrap();
+        File::options()
+            .write(true)
+            .open(file)
+            .unwrap()
+            .set_len(40_000)
+            .unwrap();
+    });
+}
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn growing_after_shrinking_doesnt_bring_the_tail_back() {
+    compare("truncate-shrink-grow", |file| {
+        fs::write(file, pattern(100_000)).unwrap();
+
+        let handle = File::options().write(true).open(file).unwrap();
+        handle.set_len(1_000).unwrap();
+        handle.set_len(100_000).unwrap();
+    });
+}
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn writing_past_the_end_after_shrinking_leaves_zeros() {
+    compare("truncate-shrink-write", |file| {
+        fs::write(file, pattern(100_000)).unwrap();
+
+        let mut handle = OpenOptions::new().write(true).open(file).unwrap();
+        handle.set_len(10).unwrap();
+
+        handle.seek(SeekFrom::Start(60_000)).unwrap();
+        handle.write_all(b"tail").unwrap();
+    });
+}
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn opening_with_o_trunc_empties_the_file() {
+    compare("truncate-o-trunc", |file| {
+        fs::write(file, pattern(50_000)).unwrap();
+
+        let mut handle = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(file)
+            .unwrap();
+        handle.write_all(b"short").unwrap();
+    });
+}
+
+#[test]
+#[ignore = "needs a mounted image, see tests/common/mod.rs"]
+fn shrinking_gives_the_tail_back() {
+    let scratch = Scratch::on_image("truncate-free");
+    let file = scratch.path.join("file");
+
+    fs::write(&file, pattern(1_000_000)).unwrap();
+    let before = free_bytes(&scratch.path);
+
+    File::options()
+        .write(true)
+        .open(&file)
+        .unwrap()
+        .set_len(1_000)
+        .unwrap();
+    let after = free_bytes(&scratch.path);
+
+    assert!(
+        after >= before + 900_000,
+        "{before} free before, {after} after"
+    );
+    assert_eq!(fs::read(&file).unwrap(), pattern(1_000));
+}